@@ -0,0 +1,305 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! The on-disk format backing a `KeyValueDatabase`.
+//!
+//! LMDB is the default: fast, but backed by a native library that can
+//! corrupt or simply refuse to open on some filesystems.  rkv also exposes
+//! `SafeMode`, a pure-Rust environment with the same `Value`/store surface,
+//! which `getOrCreateWithOptions` callers can opt into for profiles where
+//! that matters more than LMDB's performance.  `#[derive(xpcom)]` can't be
+//! applied to a generic struct, so `KeyValueDatabase` can't be parameterized
+//! over the backend's environment/database types directly; instead the two
+//! concrete instantiations are wrapped in this non-generic `Backend` enum,
+//! and every operation dispatches across it.
+
+use crate::{error::KeyValueError, ownedvalue::value_to_owned, OwnedValue};
+#[cfg(feature = "safemode")]
+use rkv::backend::{SafeMode, SafeModeDatabase, SafeModeEnvironment};
+use rkv::{
+    backend::{Lmdb, LmdbDatabase, LmdbEnvironment},
+    Manager, Rkv, SingleStore, StoreError, StoreOptions, Value,
+};
+use std::{
+    path::Path,
+    str,
+    sync::{Arc, RwLock},
+};
+
+/// Selects which `Backend` `KeyValueService::get_or_create_with_options`
+/// opens.  Mirrors the `backend` argument's values in nsIKeyValueService.idl.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KeyValueBackend {
+    Lmdb,
+    #[cfg(feature = "safemode")]
+    SafeMode,
+}
+
+impl KeyValueBackend {
+    pub(crate) fn from_u16(value: u16) -> Result<KeyValueBackend, KeyValueError> {
+        match value {
+            0 => Ok(KeyValueBackend::Lmdb),
+            #[cfg(feature = "safemode")]
+            1 => Ok(KeyValueBackend::SafeMode),
+            _unsupported => Err(KeyValueError::UnsupportedType(value)),
+        }
+    }
+}
+
+impl Default for KeyValueBackend {
+    fn default() -> KeyValueBackend {
+        KeyValueBackend::Lmdb
+    }
+}
+
+pub(crate) enum Backend {
+    Lmdb(Arc<RwLock<Rkv<LmdbEnvironment>>>, SingleStore<LmdbDatabase>),
+    #[cfg(feature = "safemode")]
+    Safe(Arc<RwLock<Rkv<SafeModeEnvironment>>>, SingleStore<SafeModeDatabase>),
+}
+
+impl Clone for Backend {
+    fn clone(&self) -> Backend {
+        match self {
+            Backend::Lmdb(rkv, store) => Backend::Lmdb(rkv.clone(), *store),
+            #[cfg(feature = "safemode")]
+            Backend::Safe(rkv, store) => Backend::Safe(rkv.clone(), *store),
+        }
+    }
+}
+
+/// Opens (creating if necessary) the environment at `path` and the named
+/// store within it, using whichever backend `backend` selects.  An empty
+/// `name` opens the environment's default, unnamed store.
+pub(crate) fn open(
+    path: &Path,
+    name: &str,
+    backend: KeyValueBackend,
+) -> Result<Backend, KeyValueError> {
+    match backend {
+        KeyValueBackend::Lmdb => {
+            let mut manager = Manager::<LmdbEnvironment>::singleton().write()?;
+            let rkv = manager.get_or_create(path, Rkv::new)?;
+            let store = match name {
+                "" => rkv.read()?.open_single(None, StoreOptions::create()),
+                _ => rkv.read()?.open_single(name, StoreOptions::create()),
+            }?;
+            Ok(Backend::Lmdb(rkv, store))
+        }
+        #[cfg(feature = "safemode")]
+        KeyValueBackend::SafeMode => {
+            let mut manager = Manager::<SafeModeEnvironment>::singleton().write()?;
+            let rkv = manager.get_or_create(path, Rkv::new)?;
+            let store = match name {
+                "" => rkv.read()?.open_single(None, StoreOptions::create()),
+                _ => rkv.read()?.open_single(name, StoreOptions::create()),
+            }?;
+            Ok(Backend::Safe(rkv, store))
+        }
+    }
+}
+
+impl Backend {
+    pub(crate) fn put(&self, key: &str, value: &Value) -> Result<(), KeyValueError> {
+        match self {
+            Backend::Lmdb(rkv, store) => {
+                let mut writer = rkv.write()?.write()?;
+                store.put(&mut writer, key, value)?;
+                writer.commit()?;
+            }
+            #[cfg(feature = "safemode")]
+            Backend::Safe(rkv, store) => {
+                let mut writer = rkv.write()?.write()?;
+                store.put(&mut writer, key, value)?;
+                writer.commit()?;
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn delete(&self, key: &str) -> Result<(), KeyValueError> {
+        match self {
+            Backend::Lmdb(rkv, store) => {
+                let mut writer = rkv.write()?.write()?;
+                match store.delete(&mut writer, key) {
+                    Ok(()) => (),
+                    // LMDB fails with an error if the key to delete wasn't
+                    // found, and Rkv returns that error, but we ignore it,
+                    // as we expect most of our consumers to want this
+                    // behavior.
+                    Err(StoreError::LmdbError(lmdb::Error::NotFound)) => (),
+                    Err(err) => return Err(err.into()),
+                };
+                writer.commit()?;
+            }
+            #[cfg(feature = "safemode")]
+            Backend::Safe(rkv, store) => {
+                let mut writer = rkv.write()?.write()?;
+                match store.delete(&mut writer, key) {
+                    Ok(()) => (),
+                    Err(StoreError::KeyValuePairNotFound) => (),
+                    Err(err) => return Err(err.into()),
+                };
+                writer.commit()?;
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn has(&self, key: &str) -> Result<bool, KeyValueError> {
+        match self {
+            Backend::Lmdb(rkv, store) => {
+                let reader = rkv.read()?.read()?;
+                Ok(store.get(&reader, key)?.is_some())
+            }
+            #[cfg(feature = "safemode")]
+            Backend::Safe(rkv, store) => {
+                let reader = rkv.read()?.read()?;
+                Ok(store.get(&reader, key)?.is_some())
+            }
+        }
+    }
+
+    pub(crate) fn get(&self, key: &str) -> Result<Option<OwnedValue>, KeyValueError> {
+        match self {
+            Backend::Lmdb(rkv, store) => {
+                let reader = rkv.read()?.read()?;
+                match store.get(&reader, key)? {
+                    Some(value) => Ok(Some(value_to_owned(Ok(Some(value)))?)),
+                    None => Ok(None),
+                }
+            }
+            #[cfg(feature = "safemode")]
+            Backend::Safe(rkv, store) => {
+                let reader = rkv.read()?.read()?;
+                match store.get(&reader, key)? {
+                    Some(value) => Ok(Some(value_to_owned(Ok(Some(value)))?)),
+                    None => Ok(None),
+                }
+            }
+        }
+    }
+
+    /// Applies every `(key, value)` pair in `pairs` within a single writer,
+    /// committing once at the end so the whole batch is atomic and costs one
+    /// fsync instead of one per pair.  A `None` value is a tombstone: the
+    /// key is deleted, tolerating (like `delete`) a key that's already gone.
+    pub(crate) fn write_many(&self, pairs: &[(String, Option<OwnedValue>)]) -> Result<(), KeyValueError> {
+        match self {
+            Backend::Lmdb(rkv, store) => {
+                let mut writer = rkv.write()?.write()?;
+                for (key, value) in pairs {
+                    match value {
+                        Some(value) => store.put(&mut writer, key, &value.as_value())?,
+                        None => match store.delete(&mut writer, key) {
+                            Ok(()) => (),
+                            Err(StoreError::LmdbError(lmdb::Error::NotFound)) => (),
+                            Err(err) => return Err(err.into()),
+                        },
+                    }
+                }
+                writer.commit()?;
+            }
+            #[cfg(feature = "safemode")]
+            Backend::Safe(rkv, store) => {
+                let mut writer = rkv.write()?.write()?;
+                for (key, value) in pairs {
+                    match value {
+                        Some(value) => store.put(&mut writer, key, &value.as_value())?,
+                        None => match store.delete(&mut writer, key) {
+                            Ok(()) => (),
+                            Err(StoreError::KeyValuePairNotFound) => (),
+                            Err(err) => return Err(err.into()),
+                        },
+                    }
+                }
+                writer.commit()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Deletes every key in the store within a single transaction.
+    pub(crate) fn clear(&self) -> Result<(), KeyValueError> {
+        match self {
+            Backend::Lmdb(rkv, store) => {
+                let mut writer = rkv.write()?.write()?;
+                store.clear(&mut writer)?;
+                writer.commit()?;
+            }
+            #[cfg(feature = "safemode")]
+            Backend::Safe(rkv, store) => {
+                let mut writer = rkv.write()?.write()?;
+                store.clear(&mut writer)?;
+                writer.commit()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the first `(key, value)` pair in the store strictly after
+    /// `after` — or, if `after` is `None` (nothing's been
+    /// returned yet), at or after `from_key` (inclusive) — as long as it's
+    /// within the `to_key` upper bound (an empty bound is unbounded on
+    /// that side).  `SimpleEnumerator` calls this once per `getNext()`
+    /// rather than collecting the whole range up front, so enumerating a
+    /// million-row range costs O(1) memory; the cost is reopening a
+    /// short-lived read transaction and reseeking the cursor on every call,
+    /// since the cursor can't outlive the transaction it came from.
+    pub(crate) fn next_entry(
+        &self,
+        from_key: &str,
+        after: Option<&str>,
+        to_key: &str,
+    ) -> Result<Option<(String, Result<OwnedValue, KeyValueError>)>, KeyValueError> {
+        let seek_key = after.unwrap_or(from_key);
+        match self {
+            Backend::Lmdb(rkv, store) => {
+                let reader = rkv.read()?.read()?;
+                let iter = if seek_key.is_empty() {
+                    store.iter_start(&reader)?
+                } else {
+                    store.iter_from(&reader, seek_key)?
+                };
+                Ok(first_after(iter, after, to_key))
+            }
+            #[cfg(feature = "safemode")]
+            Backend::Safe(rkv, store) => {
+                let reader = rkv.read()?.read()?;
+                let iter = if seek_key.is_empty() {
+                    store.iter_start(&reader)?
+                } else {
+                    store.iter_from(&reader, seek_key)?
+                };
+                Ok(first_after(iter, after, to_key))
+            }
+        }
+    }
+}
+
+fn first_after<'r, I>(
+    mut iter: I,
+    after: Option<&str>,
+    to_key: &str,
+) -> Option<(String, Result<OwnedValue, KeyValueError>)>
+where
+    I: Iterator<Item = (&'r [u8], Result<Option<Value<'r>>, StoreError>)>,
+{
+    let mut item = iter.next()?;
+
+    // iter_from(seek_key) is inclusive, so when we're continuing after a
+    // key we already returned, skip over it if the cursor landed on it.
+    if let Some(after) = after {
+        if unsafe { str::from_utf8_unchecked(item.0) } == after {
+            item = iter.next()?;
+        }
+    }
+
+    let key = unsafe { str::from_utf8_unchecked(item.0) };
+    if !to_key.is_empty() && key > to_key {
+        return None;
+    }
+
+    Some((key.to_owned(), value_to_owned(item.1)))
+}
@@ -0,0 +1,87 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use nserror::{nsresult, NS_ERROR_FAILURE, NS_ERROR_NOT_AVAILABLE, NS_ERROR_UNEXPECTED};
+use rkv::StoreError as RkvStoreError;
+use std::{
+    str::Utf8Error,
+    string::FromUtf16Error,
+    sync::PoisonError,
+};
+
+#[derive(Debug, Fail)]
+pub enum KeyValueError {
+    #[fail(display = "error converting bytes: {:?}", _0)]
+    ConvertBytes(Utf8Error),
+
+    #[fail(display = "error converting string: {:?}", _0)]
+    ConvertString(FromUtf16Error),
+
+    #[fail(display = "QueryInterface failed for {}", _0)]
+    NoInterface(&'static str),
+
+    #[fail(display = "poison error getting read/write lock")]
+    PoisonError,
+
+    #[fail(display = "error reading value")]
+    Read,
+
+    #[fail(display = "error result {:?}", _0)]
+    Nsresult(nsresult),
+
+    #[fail(display = "store error: {:?}", _0)]
+    StoreError(RkvStoreError),
+
+    #[fail(display = "unexpected value")]
+    UnexpectedValue,
+
+    #[fail(display = "unsupported variant type: {}", _0)]
+    UnsupportedType(u16),
+}
+
+impl From<KeyValueError> for nsresult {
+    fn from(err: KeyValueError) -> nsresult {
+        match err {
+            KeyValueError::ConvertBytes(_) => NS_ERROR_FAILURE,
+            KeyValueError::ConvertString(_) => NS_ERROR_FAILURE,
+            KeyValueError::NoInterface(_) => NS_ERROR_FAILURE,
+            KeyValueError::PoisonError => NS_ERROR_UNEXPECTED,
+            KeyValueError::Read => NS_ERROR_FAILURE,
+            KeyValueError::Nsresult(result) => result,
+            KeyValueError::StoreError(_) => NS_ERROR_FAILURE,
+            KeyValueError::UnexpectedValue => NS_ERROR_UNEXPECTED,
+            KeyValueError::UnsupportedType(_) => NS_ERROR_NOT_AVAILABLE,
+        }
+    }
+}
+
+impl From<nsresult> for KeyValueError {
+    fn from(result: nsresult) -> KeyValueError {
+        KeyValueError::Nsresult(result)
+    }
+}
+
+impl<T> From<PoisonError<T>> for KeyValueError {
+    fn from(_: PoisonError<T>) -> KeyValueError {
+        KeyValueError::PoisonError
+    }
+}
+
+impl From<RkvStoreError> for KeyValueError {
+    fn from(err: RkvStoreError) -> KeyValueError {
+        KeyValueError::StoreError(err)
+    }
+}
+
+impl From<Utf8Error> for KeyValueError {
+    fn from(err: Utf8Error) -> KeyValueError {
+        KeyValueError::ConvertBytes(err)
+    }
+}
+
+impl From<FromUtf16Error> for KeyValueError {
+    fn from(err: FromUtf16Error) -> KeyValueError {
+        KeyValueError::ConvertString(err)
+    }
+}
@@ -8,6 +8,7 @@ extern crate libc;
 extern crate lmdb;
 #[macro_use]
 extern crate log;
+extern crate moz_task;
 extern crate nserror;
 extern crate nsstring;
 extern crate ordered_float;
@@ -16,29 +17,29 @@ extern crate storage_variant;
 #[macro_use]
 extern crate xpcom;
 
+mod backend;
 mod error;
 mod ownedvalue;
+mod task;
 
+use backend::{Backend, KeyValueBackend};
 use error::KeyValueError;
 use libc::{c_double, c_void, int32_t, int64_t, uint16_t};
+use moz_task::{create_background_task_queue, Task, TaskRunnable};
 use nserror::{
     nsresult, NsresultExt, NS_ERROR_FAILURE, NS_ERROR_NOT_IMPLEMENTED, NS_ERROR_NO_AGGREGATION,
     NS_OK,
 };
 use nsstring::{nsACString, nsCString, nsString};
-use ownedvalue::{OwnedValue, value_to_owned};
-use rkv::{Manager, Rkv, Store, StoreError, Value};
-use std::{
-    cell::RefCell,
-    path::Path,
-    ptr, str,
-    sync::{Arc, RwLock},
-    vec::IntoIter,
-};
-use storage_variant::{IntoVariant, Variant};
+use ownedvalue::OwnedValue;
+use std::{cell::RefCell, mem, path::Path, ptr, slice, str};
+use storage_variant::IntoVariant;
 use xpcom::{
+    getter_addrefs,
     interfaces::{
-        nsIJSEnumerator, nsIKeyValueDatabase, nsISimpleEnumerator, nsISupports, nsIVariant,
+        nsIEventTarget, nsIJSEnumerator, nsIKeyValueDatabase, nsIKeyValueEnumeratorCallback,
+        nsIKeyValuePair, nsIKeyValueVariantCallback, nsIKeyValueVoidCallback, nsISimpleEnumerator,
+        nsISupports, nsIVariant,
     },
     nsIID, Ensure, RefPtr,
 };
@@ -49,8 +50,10 @@ use xpcom::{
 #[allow(non_camel_case_types)]
 enum DataType {
     INT32 = 2,
+    UINT8 = 4,
     DOUBLE = 9,
     BOOL = 10,
+    ARRAY = 19,
     WSTRING = 21,
     EMPTY = 255,
 }
@@ -69,8 +72,10 @@ enum DataType {
 // seems sufficient.)
 //
 const DATA_TYPE_INT32: uint16_t = DataType::INT32 as u16;
+const DATA_TYPE_UINT8: uint16_t = DataType::UINT8 as u16;
 const DATA_TYPE_DOUBLE: uint16_t = DataType::DOUBLE as u16;
 const DATA_TYPE_BOOL: uint16_t = DataType::BOOL as u16;
+const DATA_TYPE_ARRAY: uint16_t = DataType::ARRAY as u16;
 const DATA_TYPE_WSTRING: uint16_t = DataType::WSTRING as u16;
 const DATA_TYPE_EMPTY: uint16_t = DataType::EMPTY as u16;
 
@@ -131,21 +136,40 @@ impl KeyValueService {
         { path: *const nsACString, name: *const nsACString },
         *mut *const nsIKeyValueDatabase
     );
+    xpcom_method!(
+        GetOrCreateWithOptions,
+        get_or_create_with_options,
+        { path: *const nsACString, name: *const nsACString, backend: uint16_t },
+        *mut *const nsIKeyValueDatabase
+    );
 
     fn get_or_create(
         &self,
         path: &nsACString,
         name: &nsACString,
+    ) -> Result<RefPtr<nsIKeyValueDatabase>, KeyValueError> {
+        self.get_or_create_with(path, name, KeyValueBackend::Lmdb)
+    }
+
+    fn get_or_create_with_options(
+        &self,
+        path: &nsACString,
+        name: &nsACString,
+        backend: uint16_t,
+    ) -> Result<RefPtr<nsIKeyValueDatabase>, KeyValueError> {
+        self.get_or_create_with(path, name, KeyValueBackend::from_u16(backend)?)
+    }
+
+    fn get_or_create_with(
+        &self,
+        path: &nsACString,
+        name: &nsACString,
+        backend: KeyValueBackend,
     ) -> Result<RefPtr<nsIKeyValueDatabase>, KeyValueError> {
         let path = str::from_utf8(path)?;
         let name = str::from_utf8(name)?;
-        let mut writer = Manager::singleton().write()?;
-        let rkv = writer.get_or_create(Path::new(path), Rkv::new)?;
-        let store = match name {
-            "" => rkv.write()?.open_or_create_default(),
-            _ => rkv.write()?.open_or_create(Some(name)),
-        }?;
-        let key_value_db = KeyValueDatabase::new(rkv, store);
+        let backend = backend::open(Path::new(path), name, backend)?;
+        let key_value_db = KeyValueDatabase::new(backend)?;
 
         key_value_db
             .query_interface::<nsIKeyValueDatabase>()
@@ -157,19 +181,34 @@ impl KeyValueService {
 #[xpimplements(nsIKeyValueDatabase)]
 #[refcnt = "nonatomic"]
 pub struct InitKeyValueDatabase {
-    rkv: Arc<RwLock<Rkv>>,
-    store: Store,
+    backend: Backend,
+    queue: RefPtr<nsIEventTarget>,
 }
 
 impl KeyValueDatabase {
-    fn new(rkv: Arc<RwLock<Rkv>>, store: Store) -> RefPtr<KeyValueDatabase> {
-        KeyValueDatabase::allocate(InitKeyValueDatabase { rkv, store })
+    fn new(backend: Backend) -> Result<RefPtr<KeyValueDatabase>, KeyValueError> {
+        // A serial background queue, rather than a dedicated thread, is
+        // enough to get LMDB's page faults and fsyncs off the caller's
+        // thread without paying for a thread per database.
+        let queue = create_background_task_queue("KVDatabase")?;
+        Ok(KeyValueDatabase::allocate(InitKeyValueDatabase {
+            backend,
+            queue,
+        }))
     }
 
-    xpcom_method!(Put, put, { key: *const nsACString, value: *const nsIVariant });
-    xpcom_method!(Has, has, { key: *const nsACString }, *mut bool);
-    xpcom_method!(Get, get, { key: *const nsACString, default_value: *const nsIVariant }, *mut *const nsIVariant);
-    xpcom_method!(Delete, delete, { key: *const nsACString });
+    fn dispatch(&self, task: Box<dyn Task + Send>) -> Result<(), KeyValueError> {
+        let runnable = TaskRunnable::new(task)?;
+        runnable.dispatch(&self.queue)?;
+        Ok(())
+    }
+
+    xpcom_method!(Put, put, { key: *const nsACString, value: *const nsIVariant, callback: *const nsIKeyValueVoidCallback });
+    xpcom_method!(Has, has, { key: *const nsACString, callback: *const nsIKeyValueVariantCallback });
+    xpcom_method!(Get, get, { key: *const nsACString, default_value: *const nsIVariant, callback: *const nsIKeyValueVariantCallback });
+    xpcom_method!(Delete, delete, { key: *const nsACString, callback: *const nsIKeyValueVoidCallback });
+    xpcom_method!(WriteMany, write_many, { pairs: *const nsISimpleEnumerator, callback: *const nsIKeyValueVoidCallback });
+    xpcom_method!(Clear, clear, { callback: *const nsIKeyValueVoidCallback });
     xpcom_method!(GetInt, get_int, { key: *const nsACString, default_value: int64_t }, *mut int64_t);
     xpcom_method!(GetDouble, get_double, { key: *const nsACString, default_value: c_double }, *mut c_double);
     xpcom_method!(GetBool, get_bool, { key: *const nsACString, default_value: bool }, *mut bool);
@@ -177,118 +216,100 @@ impl KeyValueDatabase {
     xpcom_method!(
         Enumerate,
         enumerate,
-        { from_key: *const nsACString, to_key: *const nsACString },
-        *mut *const nsISimpleEnumerator
+        { from_key: *const nsACString, to_key: *const nsACString, callback: *const nsIKeyValueEnumeratorCallback }
     );
 
-    fn put(&self, key: &nsACString, value: &nsIVariant) -> Result<(), KeyValueError> {
-        let key = str::from_utf8(key)?;
-
-        let mut data_type: uint16_t = 0;
-        unsafe { value.GetDataType(&mut data_type) }.to_result()?;
-        info!("nsIVariant type is {}", data_type);
-
-        let env = self.rkv.read()?;
-        let mut writer = env.write()?;
-
-        match data_type {
-            DATA_TYPE_INT32 => {
-                info!("nsIVariant type is int32");
-                let mut value_as_int32: int32_t = 0;
-                unsafe { value.GetAsInt32(&mut value_as_int32) }.to_result()?;
-                writer.put(&self.store, key, &Value::I64(value_as_int32.into()))?;
-                writer.commit()?;
-            }
-            DATA_TYPE_DOUBLE => {
-                info!("nsIVariant type is double");
-                let mut value_as_double: f64 = 0.0;
-                unsafe { value.GetAsDouble(&mut value_as_double) }.to_result()?;
-                writer.put(&self.store, key, &Value::F64(value_as_double.into()))?;
-                writer.commit()?;
-            }
-            DATA_TYPE_WSTRING => {
-                info!("nsIVariant type is string");
-                let mut value_as_astring: nsString = nsString::new();
-                unsafe { value.GetAsAString(&mut *value_as_astring) }.to_result()?;
-                let value = String::from_utf16(&value_as_astring)?;
-                writer.put(&self.store, key, &Value::Str(&value))?;
-                writer.commit()?;
-            }
-            DATA_TYPE_BOOL => {
-                info!("nsIVariant type is bool");
-                let mut value_as_bool: bool = false;
-                unsafe { value.GetAsBool(&mut value_as_bool) }.to_result()?;
-                writer.put(&self.store, key, &Value::Bool(value_as_bool.into()))?;
-                writer.commit()?;
-            }
-            _unsupported_type => {
-                return Err(KeyValueError::UnsupportedType(data_type));
-            }
-        };
-
-        Ok(())
+    fn put(
+        &self,
+        key: &nsACString,
+        value: &nsIVariant,
+        callback: &nsIKeyValueVoidCallback,
+    ) -> Result<(), KeyValueError> {
+        let key = str::from_utf8(key)?.to_owned();
+        let value = owned_value_from_variant(value)?;
+
+        self.dispatch(Box::new(task::PutTask::new(
+            self.backend.clone(),
+            key,
+            value,
+            RefPtr::new(callback),
+        )))
     }
 
-    fn has(&self, key: &nsACString) -> Result<bool, KeyValueError> {
-        let key = str::from_utf8(key)?;
-        let env = self.rkv.read()?;
-        let reader = env.read()?;
-        let value = reader.get(&self.store, key)?;
-        Ok(value.is_some())
+    fn has(
+        &self,
+        key: &nsACString,
+        callback: &nsIKeyValueVariantCallback,
+    ) -> Result<(), KeyValueError> {
+        let key = str::from_utf8(key)?.to_owned();
+
+        self.dispatch(Box::new(task::HasTask::new(
+            self.backend.clone(),
+            key,
+            RefPtr::new(callback),
+        )))
     }
 
     fn get(
         &self,
         key: &nsACString,
         default_value: &nsIVariant,
-    ) -> Result<RefPtr<nsIVariant>, KeyValueError> {
-        let key = str::from_utf8(key)?;
-        let env = self.rkv.read()?;
-        let reader = env.read()?;
-        let value = reader.get(&self.store, key)?;
-
-        match value {
-            Some(Value::I64(value)) => Ok(value.into_variant().ok_or(KeyValueError::Read)?.take()),
-            Some(Value::F64(value)) => Ok(value.into_variant().ok_or(KeyValueError::Read)?.take()),
-            Some(Value::Str(value)) => Ok(nsString::from(value)
-                .into_variant()
-                .ok_or(KeyValueError::Read)?
-                .take()),
-            Some(Value::Bool(value)) => Ok(value.into_variant().ok_or(KeyValueError::Read)?.take()),
-            Some(_value) => Err(KeyValueError::UnexpectedValue),
-            None => Ok(into_variant(default_value)?.take()),
-        }
+        callback: &nsIKeyValueVariantCallback,
+    ) -> Result<(), KeyValueError> {
+        let key = str::from_utf8(key)?.to_owned();
+        // default_value arrives as an EMPTY variant both when the caller
+        // explicitly passes one and when XPConnect fills in an omitted
+        // `[optional]` JS argument, so it means "no default" either way.
+        let default_value = owned_value_from_variant_opt(default_value)?;
+
+        self.dispatch(Box::new(task::GetTask::new(
+            self.backend.clone(),
+            key,
+            default_value,
+            RefPtr::new(callback),
+        )))
     }
 
-    fn delete(&self, key: &nsACString) -> Result<(), KeyValueError> {
-        let key = str::from_utf8(key)?;
-        let env = self.rkv.read()?;
-        let mut writer = env.write()?;
-
-        match writer.delete(&self.store, key) {
-            Ok(_) => (),
-
-            // LMDB fails with an error if the key to delete wasn't found,
-            // and Rkv returns that error, but we ignore it, as we expect most
-            // of our consumers to want this behavior.
-            Err(StoreError::LmdbError(lmdb::Error::NotFound)) => (),
-
-            Err(err) => return Err(KeyValueError::StoreError(err)),
-        };
+    fn delete(
+        &self,
+        key: &nsACString,
+        callback: &nsIKeyValueVoidCallback,
+    ) -> Result<(), KeyValueError> {
+        let key = str::from_utf8(key)?.to_owned();
+
+        self.dispatch(Box::new(task::DeleteTask::new(
+            self.backend.clone(),
+            key,
+            RefPtr::new(callback),
+        )))
+    }
 
-        writer.commit()?;
+    fn write_many(
+        &self,
+        pairs: &nsISimpleEnumerator,
+        callback: &nsIKeyValueVoidCallback,
+    ) -> Result<(), KeyValueError> {
+        let pairs = collect_write_pairs(pairs)?;
+
+        self.dispatch(Box::new(task::WriteManyTask::new(
+            self.backend.clone(),
+            pairs,
+            RefPtr::new(callback),
+        )))
+    }
 
-        Ok(())
+    fn clear(&self, callback: &nsIKeyValueVoidCallback) -> Result<(), KeyValueError> {
+        self.dispatch(Box::new(task::ClearTask::new(
+            self.backend.clone(),
+            RefPtr::new(callback),
+        )))
     }
 
     fn get_int(&self, key: &nsACString, default_value: int64_t) -> Result<int64_t, KeyValueError> {
         let key = str::from_utf8(key)?;
-        let env = self.rkv.read()?;
-        let reader = env.read()?;
-        let value = reader.get(&self.store, &key)?;
 
-        match value {
-            Some(Value::I64(value)) => Ok(value),
+        match self.backend.get(key)? {
+            Some(OwnedValue::I64(value)) => Ok(value),
             Some(_value) => Err(KeyValueError::UnexpectedValue),
             None => Ok(default_value),
         }
@@ -300,12 +321,9 @@ impl KeyValueDatabase {
         default_value: c_double,
     ) -> Result<c_double, KeyValueError> {
         let key = str::from_utf8(key)?;
-        let env = self.rkv.read()?;
-        let reader = env.read()?;
-        let value = reader.get(&self.store, &key)?;
 
-        match value {
-            Some(Value::F64(value)) => Ok(value.into()),
+        match self.backend.get(key)? {
+            Some(OwnedValue::F64(value)) => Ok(value.into_inner()),
             Some(_value) => Err(KeyValueError::UnexpectedValue),
             None => Ok(default_value),
         }
@@ -317,12 +335,9 @@ impl KeyValueDatabase {
         default_value: &nsACString,
     ) -> Result<nsCString, KeyValueError> {
         let key = str::from_utf8(key)?;
-        let env = self.rkv.read()?;
-        let reader = env.read()?;
-        let value = reader.get(&self.store, &key)?;
 
-        match value {
-            Some(Value::Str(value)) => Ok(nsCString::from(value)),
+        match self.backend.get(key)? {
+            Some(OwnedValue::Str(value)) => Ok(nsCString::from(value)),
             Some(_value) => Err(KeyValueError::UnexpectedValue),
             None => Ok(nsCString::from(default_value)),
         }
@@ -330,12 +345,9 @@ impl KeyValueDatabase {
 
     fn get_bool(&self, key: &nsACString, default_value: bool) -> Result<bool, KeyValueError> {
         let key = str::from_utf8(key)?;
-        let env = self.rkv.read()?;
-        let reader = env.read()?;
-        let value = reader.get(&self.store, &key)?;
 
-        match value {
-            Some(Value::Bool(value)) => Ok(value),
+        match self.backend.get(key)? {
+            Some(OwnedValue::Bool(value)) => Ok(value),
             Some(_value) => Err(KeyValueError::UnexpectedValue),
             None => Ok(default_value),
         }
@@ -345,48 +357,17 @@ impl KeyValueDatabase {
         &self,
         from_key: &nsACString,
         to_key: &nsACString,
-    ) -> Result<RefPtr<nsISimpleEnumerator>, KeyValueError> {
-        let env = self.rkv.read()?;
-        let reader = env.read()?;
-        let from_key = str::from_utf8(from_key)?;
-        let to_key = str::from_utf8(to_key)?;
-
-        let iterator = if from_key.is_empty() {
-            reader.iter_start(&self.store)?
-        } else {
-            reader.iter_from(&self.store, &from_key)?
-        };
-
-        // Ideally, we'd iterate pairs lazily, as the consumer calls
-        // nsISimpleEnumerator.getNext().  But SimpleEnumerator can't reference
-        // the Iter because Rust "cannot #[derive(xpcom)] on a generic type,"
-        // and the Iter requires a lifetime parameter, which would make
-        // SimpleEnumerator generic.
-        //
-        // Our fallback approach is to collect the iterator into a collection
-        // that SimpleEnumerator owns.
-        //
-        let pairs: Vec<(String, Result<OwnedValue, KeyValueError>)> = iterator
-            .map(|(key, val)| {
-                (
-                    unsafe { str::from_utf8_unchecked(&key) },
-                    val,
-                )
-            })
-            .take_while(|(key, _val)| if to_key.is_empty() { true } else { *key <= to_key })
-            .map(|(key, val)| {
-                (
-                    key.to_owned(),
-                    value_to_owned(val),
-                )
-            })
-            .collect();
-
-        let enumerator = SimpleEnumerator::new(pairs);
-
-        enumerator
-            .query_interface::<nsISimpleEnumerator>()
-            .ok_or(KeyValueError::NoInterface("nsISimpleEnumerator"))
+        callback: &nsIKeyValueEnumeratorCallback,
+    ) -> Result<(), KeyValueError> {
+        let from_key = str::from_utf8(from_key)?.to_owned();
+        let to_key = str::from_utf8(to_key)?.to_owned();
+
+        self.dispatch(Box::new(task::EnumerateTask::new(
+            self.backend.clone(),
+            from_key,
+            to_key,
+            RefPtr::new(callback),
+        )))
     }
 }
 
@@ -394,13 +375,26 @@ impl KeyValueDatabase {
 #[xpimplements(nsISimpleEnumerator)]
 #[refcnt = "nonatomic"]
 pub struct InitSimpleEnumerator {
-    iter: RefCell<IntoIter<(String, Result<OwnedValue, KeyValueError>)>>,
+    backend: Backend,
+    from_key: String,
+    to_key: String,
+    // The last key handed out via get_next, if any; the next fetch resumes
+    // strictly after it instead of reusing from_key.
+    after: RefCell<Option<String>>,
+    // A single-item lookahead, fetched from the backend on demand and
+    // consumed by get_next. Keeping this separate from `after` lets
+    // has_more_elements peek without also advancing the cursor.
+    peeked: RefCell<Option<(String, Result<OwnedValue, KeyValueError>)>>,
 }
 
 impl SimpleEnumerator {
-    fn new(pairs: Vec<(String, Result<OwnedValue, KeyValueError>)>) -> RefPtr<SimpleEnumerator> {
+    fn new(backend: Backend, from_key: String, to_key: String) -> RefPtr<SimpleEnumerator> {
         SimpleEnumerator::allocate(InitSimpleEnumerator {
-            iter: RefCell::new(pairs.into_iter()),
+            backend,
+            from_key,
+            to_key,
+            after: RefCell::new(None),
+            peeked: RefCell::new(None),
         })
     }
 
@@ -418,23 +412,37 @@ impl SimpleEnumerator {
         NS_ERROR_NOT_IMPLEMENTED
     }
 
+    fn ensure_peeked(&self) -> Result<(), KeyValueError> {
+        if self.peeked.borrow().is_some() {
+            return Ok(());
+        }
+        let next =
+            self.backend
+                .next_entry(&self.from_key, self.after.borrow().as_deref(), &self.to_key)?;
+        *self.peeked.borrow_mut() = next;
+        Ok(())
+    }
+
     fn has_more_elements(&self) -> Result<bool, KeyValueError> {
-        Ok(!self.iter.borrow().as_slice().is_empty())
+        self.ensure_peeked()?;
+        Ok(self.peeked.borrow().is_some())
     }
 
     fn get_next(&self) -> Result<RefPtr<nsISupports>, KeyValueError> {
-        let mut iter = self.iter.borrow_mut();
-        let (key, value) = iter
-            .next()
+        self.ensure_peeked()?;
+        let (key, value) = self
+            .peeked
+            .borrow_mut()
+            .take()
             .ok_or(KeyValueError::from(NS_ERROR_FAILURE))?;
+        *self.after.borrow_mut() = Some(key.clone());
 
         // We fail on retrieval of the key/value pair if the value
         // is unexpected or we encountered a store error while retrieving it.
         //
         // We could fail eagerly—when instantiating the enumerator, but that
-        // would expose the implementation detail that we eagerly collect
-        // the results of the cursor iterator, which we plan to stop doing
-        // in the future.
+        // would require collecting the whole range up front, which is what
+        // this streaming design avoids.
         //
         // We could also fail more lazily—on nsIKeyValuePair.getValue(),
         // but that would hide errors when the consumer enumerates pairs
@@ -476,39 +484,104 @@ impl KeyValuePair {
     }
 }
 
-// TODO: consider making this an implementation of the IntoVariant trait
-// from storage/variant/src/lib.rs.
-fn into_variant(variant: &nsIVariant) -> Result<Variant, KeyValueError> {
+// Copies an nsIVariant's value out into an OwnedValue so it can outlive the
+// XPCOM call and travel to the background task queue with the rest of a
+// task's arguments.  EMPTY isn't representable as an OwnedValue (there's no
+// "no value" variant); callers that might legitimately see an EMPTY variant
+// (a caller-supplied default, a WriteMany tombstone) go through
+// owned_value_from_variant_opt instead.
+fn owned_value_from_variant(variant: &nsIVariant) -> Result<OwnedValue, KeyValueError> {
     let mut data_type: uint16_t = 0;
     unsafe { variant.GetDataType(&mut data_type) }.to_result()?;
+    info!("nsIVariant type is {}", data_type);
 
     match data_type {
         DATA_TYPE_INT32 => {
             let mut val: int32_t = 0;
             unsafe { variant.GetAsInt32(&mut val) }.to_result()?;
-            Ok(val.into_variant().ok_or(KeyValueError::Read)?)
+            Ok(OwnedValue::I64(val.into()))
         }
         DATA_TYPE_DOUBLE => {
             let mut val: f64 = 0.0;
             unsafe { variant.GetAsDouble(&mut val) }.to_result()?;
-            Ok(val.into_variant().ok_or(KeyValueError::Read)?)
+            Ok(OwnedValue::F64(val.into()))
         }
         DATA_TYPE_WSTRING => {
             let mut val: nsString = nsString::new();
             unsafe { variant.GetAsAString(&mut *val) }.to_result()?;
-            Ok(val.into_variant().ok_or(KeyValueError::Read)?)
+            Ok(OwnedValue::Str(String::from_utf16(&val)?))
         }
         DATA_TYPE_BOOL => {
             let mut val: bool = false;
             unsafe { variant.GetAsBool(&mut val) }.to_result()?;
-            Ok(val.into_variant().ok_or(KeyValueError::Read)?)
+            Ok(OwnedValue::Bool(val))
         }
-        DATA_TYPE_EMPTY => {
-            let val = ();
-            Ok(val.into_variant().ok_or(KeyValueError::Read)?)
+        DATA_TYPE_ARRAY => {
+            let mut element_type: uint16_t = 0;
+            let mut element_iid: nsIID = unsafe { mem::zeroed() };
+            let mut count: u32 = 0;
+            let mut elements: *mut c_void = ptr::null_mut();
+            unsafe { variant.GetAsArray(&mut element_type, &mut element_iid, &mut count, &mut elements) }
+                .to_result()?;
+            let bytes =
+                unsafe { slice::from_raw_parts(elements as *const u8, count as usize) }.to_vec();
+            unsafe { libc::free(elements) };
+            if element_type != DATA_TYPE_UINT8 {
+                return Err(KeyValueError::UnsupportedType(element_type));
+            }
+            Ok(OwnedValue::Blob(bytes))
         }
-        _unsupported_type => {
-            return Err(KeyValueError::UnsupportedType(data_type));
+        _unsupported_type => Err(KeyValueError::UnsupportedType(data_type)),
+    }
+}
+
+// Like owned_value_from_variant, but an EMPTY variant maps to None instead
+// of an error. WriteMany uses that to mark a key for deletion rather than a
+// put, and Get uses it to treat an EMPTY default_value as "no default",
+// whether a caller passed it explicitly or XPConnect filled it in for an
+// omitted `[optional]` argument; the two cases can't be told apart here.
+fn owned_value_from_variant_opt(
+    variant: &nsIVariant,
+) -> Result<Option<OwnedValue>, KeyValueError> {
+    let mut data_type: uint16_t = 0;
+    unsafe { variant.GetDataType(&mut data_type) }.to_result()?;
+
+    if data_type == DATA_TYPE_EMPTY {
+        return Ok(None);
+    }
+
+    owned_value_from_variant(variant).map(Some)
+}
+
+// Walks an nsISimpleEnumerator of nsIKeyValuePair, copying each key/value
+// (or key/tombstone) pair out into an owned form so the batch can be
+// applied on the background task queue within a single transaction.
+fn collect_write_pairs(
+    pairs: &nsISimpleEnumerator,
+) -> Result<Vec<(String, Option<OwnedValue>)>, KeyValueError> {
+    let mut result = Vec::new();
+
+    loop {
+        let mut has_more = false;
+        unsafe { pairs.HasMoreElements(&mut has_more) }.to_result()?;
+        if !has_more {
+            break;
         }
+
+        let supports: RefPtr<nsISupports> = getter_addrefs(|p| unsafe { pairs.GetNext(p) })?;
+        let pair = supports
+            .query_interface::<nsIKeyValuePair>()
+            .ok_or(KeyValueError::NoInterface("nsIKeyValuePair"))?;
+
+        let mut key = nsCString::new();
+        unsafe { pair.GetKey(&mut *key) }.to_result()?;
+        let key = str::from_utf8(&key)?.to_owned();
+
+        let value: RefPtr<nsIVariant> = getter_addrefs(|p| unsafe { pair.GetValue(p) })?;
+        let value = owned_value_from_variant_opt(&value)?;
+
+        result.push((key, value));
     }
+
+    Ok(result)
 }
@@ -0,0 +1,78 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! An owned, `'static` counterpart to `rkv::Value`.  `SimpleEnumerator`
+//! can't hold a borrowed `Value` (it outlives the read transaction it came
+//! from), so we copy each value out of the store into an `OwnedValue`
+//! before handing it to the enumerator.
+
+use crate::error::KeyValueError;
+use nsstring::nsString;
+use ordered_float::OrderedFloat;
+use rkv::{StoreError, Value};
+use storage_variant::{IntoVariant, Variant};
+use xpcom::RefPtr;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum OwnedValue {
+    Bool(bool),
+    I64(i64),
+    F64(OrderedFloat<f64>),
+    Str(String),
+    Blob(Vec<u8>),
+}
+
+pub(crate) fn value_to_owned(
+    val: Result<Option<Value>, StoreError>,
+) -> Result<OwnedValue, KeyValueError> {
+    match val {
+        Ok(Some(Value::Bool(value))) => Ok(OwnedValue::Bool(value)),
+        Ok(Some(Value::I64(value))) => Ok(OwnedValue::I64(value)),
+        Ok(Some(Value::F64(value))) => Ok(OwnedValue::F64(value)),
+        Ok(Some(Value::Str(value))) => Ok(OwnedValue::Str(value.to_owned())),
+        Ok(Some(Value::Blob(value))) => Ok(OwnedValue::Blob(value.to_owned())),
+        Ok(Some(_value)) => Err(KeyValueError::UnexpectedValue),
+        Ok(None) => Err(KeyValueError::Read),
+        Err(err) => Err(KeyValueError::StoreError(err)),
+    }
+}
+
+impl OwnedValue {
+    /// Borrows this value as the `rkv::Value` that `Store::put` expects.
+    pub(crate) fn as_value(&self) -> Value {
+        match self {
+            OwnedValue::Bool(value) => Value::Bool(*value),
+            OwnedValue::I64(value) => Value::I64(*value),
+            OwnedValue::F64(value) => Value::F64(*value),
+            OwnedValue::Str(value) => Value::Str(value),
+            OwnedValue::Blob(value) => Value::Blob(value),
+        }
+    }
+}
+
+impl IntoVariant for OwnedValue {
+    fn into_variant(self) -> Option<RefPtr<Variant>> {
+        match self {
+            OwnedValue::Bool(value) => value.into_variant(),
+            OwnedValue::I64(value) => value.into_variant(),
+            OwnedValue::F64(value) => value.into_inner().into_variant(),
+            OwnedValue::Str(value) => nsString::from(value).into_variant(),
+            OwnedValue::Blob(value) => value.into_variant(),
+        }
+    }
+}
+
+/// `Get`'s `default_value` is `None` when the key is missing and the caller
+/// didn't supply a default (either they passed an EMPTY variant explicitly,
+/// or omitted the `[optional]` argument and XPConnect filled one in for
+/// them). Resolve that the same way the rest of the EMPTY-variant handling
+/// here does: as an empty variant, i.e. `undefined` on the JS side.
+impl IntoVariant for Option<OwnedValue> {
+    fn into_variant(self) -> Option<RefPtr<Variant>> {
+        match self {
+            Some(value) => value.into_variant(),
+            None => ().into_variant(),
+        }
+    }
+}
@@ -2,98 +2,352 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
-#![allow(non_snake_case)]
+//! Off-thread implementations of the `nsIKeyValueDatabase` methods.
+//!
+//! Each XPCOM method builds the matching `*Task` here with owned copies of
+//! its arguments, dispatches it to the database's background task queue via
+//! `moz_task::TaskRunnable`, and returns immediately; the task does the
+//! actual work against the database's `Backend` on that queue and then
+//! redispatches back to the thread that made the call to invoke the
+//! caller's callback.
 
-extern crate xpcom;
-
-use nserror::{nsresult, NS_ERROR_FAILURE, NS_OK};
-use nsstring::{nsACString, nsCString};
-use std::{cell::Cell, fmt::Write, ptr, result};
+use crate::{backend::Backend, error::KeyValueError, ownedvalue::OwnedValue, SimpleEnumerator};
+use moz_task::{Task, ThreadBoundRefPtr};
+use std::cell::Cell;
+use storage_variant::IntoVariant;
 use xpcom::{
-    getter_addrefs,
-    interfaces::{nsIKeyValueDatabase, nsIRunnable, nsIThread},
+    interfaces::{nsIKeyValueEnumeratorCallback, nsIKeyValueVariantCallback, nsIKeyValueVoidCallback},
     RefPtr,
 };
 
-pub type Result<T> = result::Result<T, nsresult>;
-
-extern "C" {
-    fn NS_GetCurrentThreadEventTarget(result: *mut *const nsIThread) -> nsresult;
-    fn NS_NewNamedThreadWithDefaultStackSize(
-        name: *const nsACString,
-        result: *mut *const nsIThread,
-        event: *const nsIRunnable,
-    ) -> nsresult;
-}
-
-/// Returns a handle to the current thread.
-pub fn get_current_thread() -> Result<RefPtr<nsIThread>> {
-    getter_addrefs(|p| unsafe { NS_GetCurrentThreadEventTarget(p) })
-}
-
-pub fn create_thread(name: &str) -> Result<RefPtr<nsIThread>> {
-    let name: nsCString = name.into();
-    getter_addrefs(|p| unsafe { NS_NewNamedThreadWithDefaultStackSize(&*name, p, ptr::null()) })
-}
-
-/// A task is executed asynchronously on a target thread, and passes its
-/// result back to the original thread.
-pub trait Task {
-    fn run(&self) -> Result<RefPtr<nsIKeyValueDatabase>>;
-    fn done(&self, result: Result<RefPtr<nsIKeyValueDatabase>>) -> nsresult;
-}
-
-#[derive(xpcom)]
-#[xpimplements(nsIRunnable, nsINamed)]
-#[refcnt = "atomic"]
-pub struct InitTaskRunnable {
-    name: &'static str,
-    source: RefPtr<nsIThread>,
-
-    /// Holds the task, and the result of the task. The task is created on the
-    /// current thread, run on a target thread, and handled again on the
-    /// original thread; the result is mutated on the target thread and
-    /// accessed on the original thread.
-    task: Box<Task>,
-    result: Cell<Option<Result<RefPtr<nsIKeyValueDatabase>>>>,
-}
-
-impl TaskRunnable {
-    pub fn new(
-        name: &'static str,
-        source: RefPtr<nsIThread>,
-        task: Box<Task>,
-        result: Cell<Option<Result<RefPtr<nsIKeyValueDatabase>>>>,
-    ) -> RefPtr<TaskRunnable> {
-        TaskRunnable::allocate(InitTaskRunnable {
-            name,
-            source,
-            task,
-            result,
-        })
-    }
-
-    unsafe fn Run(&self) -> nsresult {
-        match self.result.take() {
-            None => {
-                // Run the task on the storage thread, store the result, and
-                // dispatch the runnable back to the source thread.
-                let result = self.task.run();
-                self.result.set(Some(result));
-                let target = getter_addrefs(|p| self.source.GetEventTarget(p)).unwrap();
-                target.DispatchFromScript(self.coerce(), 0)
-            }
-            Some(result) => {
-                // Back on the source thread, notify the task we're done.
-                self.task.done(result)
-            }
+pub(crate) struct PutTask {
+    backend: Backend,
+    key: String,
+    value: OwnedValue,
+    callback: ThreadBoundRefPtr<nsIKeyValueVoidCallback>,
+    result: Cell<Option<Result<(), KeyValueError>>>,
+}
+
+impl PutTask {
+    pub(crate) fn new(
+        backend: Backend,
+        key: String,
+        value: OwnedValue,
+        callback: RefPtr<nsIKeyValueVoidCallback>,
+    ) -> PutTask {
+        PutTask {
+            backend,
+            key,
+            value,
+            callback: ThreadBoundRefPtr::new(callback),
+            result: Cell::new(None),
+        }
+    }
+}
+
+impl Task for PutTask {
+    fn run(&self) {
+        let result = self.backend.put(&self.key, &self.value.as_value());
+        self.result.set(Some(result));
+    }
+
+    fn done(&self) -> moz_task::Result<()> {
+        let callback = match self.callback.get_ref() {
+            Some(callback) => callback,
+            None => return Ok(()),
+        };
+        match self.result.take().expect("PutTask ran before done() was called") {
+            Ok(()) => unsafe { callback.Resolve() },
+            Err(err) => unsafe { callback.Reject(err.into()) },
+        };
+        Ok(())
+    }
+
+    fn name(&self) -> &str {
+        "KeyValueDatabase::PutTask"
+    }
+}
+
+pub(crate) struct DeleteTask {
+    backend: Backend,
+    key: String,
+    callback: ThreadBoundRefPtr<nsIKeyValueVoidCallback>,
+    result: Cell<Option<Result<(), KeyValueError>>>,
+}
+
+impl DeleteTask {
+    pub(crate) fn new(
+        backend: Backend,
+        key: String,
+        callback: RefPtr<nsIKeyValueVoidCallback>,
+    ) -> DeleteTask {
+        DeleteTask {
+            backend,
+            key,
+            callback: ThreadBoundRefPtr::new(callback),
+            result: Cell::new(None),
+        }
+    }
+}
+
+impl Task for DeleteTask {
+    fn run(&self) {
+        let result = self.backend.delete(&self.key);
+        self.result.set(Some(result));
+    }
+
+    fn done(&self) -> moz_task::Result<()> {
+        let callback = match self.callback.get_ref() {
+            Some(callback) => callback,
+            None => return Ok(()),
+        };
+        match self.result.take().expect("DeleteTask ran before done() was called") {
+            Ok(()) => unsafe { callback.Resolve() },
+            Err(err) => unsafe { callback.Reject(err.into()) },
+        };
+        Ok(())
+    }
+
+    fn name(&self) -> &str {
+        "KeyValueDatabase::DeleteTask"
+    }
+}
+
+pub(crate) struct HasTask {
+    backend: Backend,
+    key: String,
+    callback: ThreadBoundRefPtr<nsIKeyValueVariantCallback>,
+    result: Cell<Option<Result<bool, KeyValueError>>>,
+}
+
+impl HasTask {
+    pub(crate) fn new(
+        backend: Backend,
+        key: String,
+        callback: RefPtr<nsIKeyValueVariantCallback>,
+    ) -> HasTask {
+        HasTask {
+            backend,
+            key,
+            callback: ThreadBoundRefPtr::new(callback),
+            result: Cell::new(None),
+        }
+    }
+}
+
+impl Task for HasTask {
+    fn run(&self) {
+        let result = self.backend.has(&self.key);
+        self.result.set(Some(result));
+    }
+
+    fn done(&self) -> moz_task::Result<()> {
+        let callback = match self.callback.get_ref() {
+            Some(callback) => callback,
+            None => return Ok(()),
+        };
+        match self.result.take().expect("HasTask ran before done() was called") {
+            Ok(value) => match value.into_variant() {
+                Some(variant) => unsafe { callback.Resolve(&*variant.take()) },
+                None => unsafe { callback.Reject(KeyValueError::Read.into()) },
+            },
+            Err(err) => unsafe { callback.Reject(err.into()) },
+        };
+        Ok(())
+    }
+
+    fn name(&self) -> &str {
+        "KeyValueDatabase::HasTask"
+    }
+}
+
+pub(crate) struct GetTask {
+    backend: Backend,
+    key: String,
+    default_value: Option<OwnedValue>,
+    callback: ThreadBoundRefPtr<nsIKeyValueVariantCallback>,
+    result: Cell<Option<Result<Option<OwnedValue>, KeyValueError>>>,
+}
+
+impl GetTask {
+    pub(crate) fn new(
+        backend: Backend,
+        key: String,
+        default_value: Option<OwnedValue>,
+        callback: RefPtr<nsIKeyValueVariantCallback>,
+    ) -> GetTask {
+        GetTask {
+            backend,
+            key,
+            default_value,
+            callback: ThreadBoundRefPtr::new(callback),
+            result: Cell::new(None),
         }
     }
+}
+
+impl Task for GetTask {
+    fn run(&self) {
+        let result = match self.backend.get(&self.key) {
+            Ok(Some(value)) => Ok(Some(value)),
+            Ok(None) => Ok(self.default_value.clone()),
+            Err(err) => Err(err),
+        };
+        self.result.set(Some(result));
+    }
+
+    fn done(&self) -> moz_task::Result<()> {
+        let callback = match self.callback.get_ref() {
+            Some(callback) => callback,
+            None => return Ok(()),
+        };
+        match self.result.take().expect("GetTask ran before done() was called") {
+            Ok(value) => match value.into_variant() {
+                Some(variant) => unsafe { callback.Resolve(&*variant.take()) },
+                None => unsafe { callback.Reject(KeyValueError::Read.into()) },
+            },
+            Err(err) => unsafe { callback.Reject(err.into()) },
+        };
+        Ok(())
+    }
+
+    fn name(&self) -> &str {
+        "KeyValueDatabase::GetTask"
+    }
+}
+
+pub(crate) struct EnumerateTask {
+    backend: Backend,
+    from_key: String,
+    to_key: String,
+    callback: ThreadBoundRefPtr<nsIKeyValueEnumeratorCallback>,
+}
+
+impl EnumerateTask {
+    pub(crate) fn new(
+        backend: Backend,
+        from_key: String,
+        to_key: String,
+        callback: RefPtr<nsIKeyValueEnumeratorCallback>,
+    ) -> EnumerateTask {
+        EnumerateTask {
+            backend,
+            from_key,
+            to_key,
+            callback: ThreadBoundRefPtr::new(callback),
+        }
+    }
+}
+
+impl Task for EnumerateTask {
+    // There's no store I/O to do upfront anymore: SimpleEnumerator streams
+    // entries from the backend lazily, one at a time, as the caller pulls
+    // them via getNext(). We still dispatch through the task queue so the
+    // callback is invoked asynchronously, like every other method here.
+    fn run(&self) {}
+
+    fn done(&self) -> moz_task::Result<()> {
+        let callback = match self.callback.get_ref() {
+            Some(callback) => callback,
+            None => return Ok(()),
+        };
+        let enumerator = SimpleEnumerator::new(
+            self.backend.clone(),
+            self.from_key.clone(),
+            self.to_key.clone(),
+        );
+        match enumerator.query_interface::<xpcom::interfaces::nsISimpleEnumerator>() {
+            Some(enumerator) => unsafe { callback.Resolve(&*enumerator) },
+            None => unsafe { callback.Reject(KeyValueError::NoInterface("nsISimpleEnumerator").into()) },
+        };
+        Ok(())
+    }
+
+    fn name(&self) -> &str {
+        "KeyValueDatabase::EnumerateTask"
+    }
+}
+
+pub(crate) struct WriteManyTask {
+    backend: Backend,
+    pairs: Vec<(String, Option<OwnedValue>)>,
+    callback: ThreadBoundRefPtr<nsIKeyValueVoidCallback>,
+    result: Cell<Option<Result<(), KeyValueError>>>,
+}
 
-    unsafe fn GetName(&self, name: *mut nsACString) -> nsresult {
-        match write!(*name, "{}", self.name) {
-            Ok(()) => NS_OK,
-            Err(_) => NS_ERROR_FAILURE,
+impl WriteManyTask {
+    pub(crate) fn new(
+        backend: Backend,
+        pairs: Vec<(String, Option<OwnedValue>)>,
+        callback: RefPtr<nsIKeyValueVoidCallback>,
+    ) -> WriteManyTask {
+        WriteManyTask {
+            backend,
+            pairs,
+            callback: ThreadBoundRefPtr::new(callback),
+            result: Cell::new(None),
         }
     }
 }
+
+impl Task for WriteManyTask {
+    fn run(&self) {
+        let result = self.backend.write_many(&self.pairs);
+        self.result.set(Some(result));
+    }
+
+    fn done(&self) -> moz_task::Result<()> {
+        let callback = match self.callback.get_ref() {
+            Some(callback) => callback,
+            None => return Ok(()),
+        };
+        match self.result.take().expect("WriteManyTask ran before done() was called") {
+            Ok(()) => unsafe { callback.Resolve() },
+            Err(err) => unsafe { callback.Reject(err.into()) },
+        };
+        Ok(())
+    }
+
+    fn name(&self) -> &str {
+        "KeyValueDatabase::WriteManyTask"
+    }
+}
+
+pub(crate) struct ClearTask {
+    backend: Backend,
+    callback: ThreadBoundRefPtr<nsIKeyValueVoidCallback>,
+    result: Cell<Option<Result<(), KeyValueError>>>,
+}
+
+impl ClearTask {
+    pub(crate) fn new(backend: Backend, callback: RefPtr<nsIKeyValueVoidCallback>) -> ClearTask {
+        ClearTask {
+            backend,
+            callback: ThreadBoundRefPtr::new(callback),
+            result: Cell::new(None),
+        }
+    }
+}
+
+impl Task for ClearTask {
+    fn run(&self) {
+        let result = self.backend.clear();
+        self.result.set(Some(result));
+    }
+
+    fn done(&self) -> moz_task::Result<()> {
+        let callback = match self.callback.get_ref() {
+            Some(callback) => callback,
+            None => return Ok(()),
+        };
+        match self.result.take().expect("ClearTask ran before done() was called") {
+            Ok(()) => unsafe { callback.Resolve() },
+            Err(err) => unsafe { callback.Reject(err.into()) },
+        };
+        Ok(())
+    }
+
+    fn name(&self) -> &str {
+        "KeyValueDatabase::ClearTask"
+    }
+}
@@ -0,0 +1,171 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Coalesces live writes instead of committing each one as it arrives.
+//!
+//! `maybe_migrate_data` already does the right thing for the one-time JSON
+//! import: open one writer, apply every entry, commit once.  This module
+//! gives ongoing attribute changes the same treatment: `queue_write`/
+//! `queue_delete` just record the pending mutation in memory, and a
+//! background task (dispatched at most once while a batch is pending)
+//! applies the whole accumulated batch in a single transaction.
+//! `flush_writes`/`clear_on_shutdown` force that batch to commit
+//! synchronously, for callers (profile/shutdown observers) that can't wait
+//! for the background task to get around to it.
+//!
+//! Queuing a write decouples "the caller's `SetTask`/`RemoveTask` callback
+//! resolved" from "the write is durable": the callback fires as soon as the
+//! mutation is recorded in `PENDING`, while the actual commit happens later,
+//! on a different queue, batched with whatever else queued up in the
+//! meantime. That's the whole point of coalescing -- but it means a commit
+//! failure (disk full, a poisoned lock, an LMDB write error) can't be
+//! reported back to whichever caller's write happened to trigger it; by the
+//! time it's known, that caller's callback has already resolved, and
+//! reporting it to the *next* caller instead would be misleading. Flush
+//! failures are therefore only logged and counted via
+//! `flush_failure_count()`, not delivered to any particular caller.
+
+use crate::{error::XULStoreResult, make_key, statics::apply_batch, value::XULStoreValue};
+use moz_task::{create_background_task_queue, Task};
+use std::{
+    collections::HashMap,
+    sync::{atomic::AtomicUsize, atomic::Ordering, Mutex},
+};
+use xpcom::{interfaces::nsIEventTarget, RefPtr};
+
+/// `None` means the key was deleted.
+pub(crate) type PendingValue = Option<XULStoreValue>;
+
+lazy_static! {
+    static ref PENDING: Mutex<HashMap<String, PendingValue>> = Mutex::new(HashMap::new());
+
+    /// The background task queue flushes run on.  A shared serial queue
+    /// (rather than a dedicated thread) is enough for a batch of small,
+    /// infrequent writes, and doesn't cost a whole thread when XULStore
+    /// isn't being written to.
+    static ref FLUSH_QUEUE: moz_task::Result<RefPtr<nsIEventTarget>> =
+        create_background_task_queue("XULStoreFlush");
+}
+
+/// Counts how many times a batch flush has failed this session. Exposed so
+/// telemetry can pick it up; repeated flush failures are worth knowing
+/// about even though no single caller is in a position to be told about
+/// any individual one (see the module docs above).
+static FLUSH_FAILURE_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+pub(crate) fn flush_failure_count() -> usize {
+    FLUSH_FAILURE_COUNT.load(Ordering::Relaxed)
+}
+
+fn report_flush_failure(context: &str, err: crate::error::XULStoreError) {
+    let count = FLUSH_FAILURE_COUNT.fetch_add(1, Ordering::Relaxed) + 1;
+    error!(
+        "error flushing XULStore writes ({}): {} ({} failures this session)",
+        context, err, count
+    );
+}
+
+/// Whether a flush task has already been dispatched for the current batch,
+/// so repeated writes between now and that flush running don't each
+/// schedule their own task.
+static FLUSH_SCHEDULED: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+pub(crate) fn queue_write(doc: &str, id: &str, attr: &str, value: XULStoreValue) {
+    let key = make_key(doc, id, attr);
+    PENDING.lock().unwrap().insert(key, Some(value));
+    schedule_flush();
+}
+
+pub(crate) fn queue_delete(doc: &str, id: &str, attr: &str) {
+    let key = make_key(doc, id, attr);
+    PENDING.lock().unwrap().insert(key, None);
+    schedule_flush();
+}
+
+/// Looks up `key` among the writes queued but not yet flushed. `Some(value)`
+/// is a pending write, `Some(None)` is a pending delete, and `None` means
+/// nothing's queued for this key, i.e. readers should fall through to the
+/// committed store. `GetTask` reads single keys through this so it sees the
+/// caller's own unflushed writes.
+pub(crate) fn peek(key: &str) -> Option<PendingValue> {
+    PENDING.lock().unwrap().get(key).cloned()
+}
+
+/// A snapshot of every currently queued mutation, for callers like
+/// `get_data` that overlay the whole pending batch onto what's already
+/// committed rather than looking up one key at a time.
+pub(crate) fn snapshot() -> HashMap<String, PendingValue> {
+    PENDING.lock().unwrap().clone()
+}
+
+fn schedule_flush() {
+    if FLUSH_SCHEDULED.swap(true, std::sync::atomic::Ordering::SeqCst) {
+        // A flush is already on its way; it'll pick up this write too.
+        return;
+    }
+
+    let queue = match &*FLUSH_QUEUE {
+        Ok(queue) => queue.clone(),
+        Err(_) => {
+            // No queue to dispatch to; fall back to flushing inline so the
+            // write isn't silently dropped.
+            FLUSH_SCHEDULED.store(false, std::sync::atomic::Ordering::SeqCst);
+            flush_writes().unwrap_or_else(|err| report_flush_failure("inline", err));
+            return;
+        }
+    };
+
+    let runnable = match moz_task::TaskRunnable::new(Box::new(FlushTask)) {
+        Ok(runnable) => runnable,
+        Err(_) => {
+            FLUSH_SCHEDULED.store(false, std::sync::atomic::Ordering::SeqCst);
+            return;
+        }
+    };
+
+    if runnable.dispatch(&queue).is_err() {
+        FLUSH_SCHEDULED.store(false, std::sync::atomic::Ordering::SeqCst);
+    }
+}
+
+struct FlushTask;
+
+impl Task for FlushTask {
+    fn run(&self) {
+        flush_writes().unwrap_or_else(|err| report_flush_failure("background", err));
+    }
+
+    fn done(&self) -> moz_task::Result<()> {
+        Ok(())
+    }
+
+    fn name(&self) -> &str {
+        "XULStore::FlushTask"
+    }
+}
+
+/// Applies every pending write in a single transaction and commits.  Safe
+/// to call from any thread; callers that need the write durable right away
+/// (shutdown) should call this directly instead of going through
+/// `schedule_flush`.
+pub(crate) fn flush_writes() -> XULStoreResult<()> {
+    FLUSH_SCHEDULED.store(false, std::sync::atomic::Ordering::SeqCst);
+
+    // Hold the lock until the batch is committed, so `peek`/`snapshot`
+    // callers never see a write that's neither pending nor committed.
+    let mut pending = PENDING.lock().unwrap();
+    let batch: HashMap<String, PendingValue> = std::mem::take(&mut *pending);
+    if batch.is_empty() {
+        return Ok(());
+    }
+
+    apply_batch(&batch)
+}
+
+/// Forces any pending writes to commit synchronously.  Called from the
+/// profile-before-change/shutdown path so nothing queued up in memory is
+/// lost when the process exits.
+pub(crate) fn clear_on_shutdown() {
+    flush_writes().unwrap_or_else(|err| report_flush_failure("at shutdown", err));
+}
@@ -0,0 +1,58 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! What to do when the on-disk `xulstore` environment turns out to be
+//! corrupt: move it out of the way, so a fresh environment can be created in
+//! its place, rather than leaving `STORE`/`DATA` permanently `None` for the
+//! rest of the session.
+
+use crate::error::XULStoreResult;
+use std::{
+    fs::rename,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// Counts how many times we've had to recover from a corrupt environment
+/// this session.  Exposed so telemetry can pick it up; repeated corruption
+/// on the same profile is worth knowing about even if each individual
+/// recovery succeeds.
+static RECOVERY_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+pub(crate) fn recovery_count() -> usize {
+    RECOVERY_COUNT.load(Ordering::Relaxed)
+}
+
+/// Moves `xulstore_dir` aside to `xulstore.corrupt` (replacing any previous
+/// one) so a fresh environment can be created at `xulstore_dir` in its
+/// place.  Returns the path data was moved to, so the caller can attempt to
+/// recover a JSON snapshot from it if one is present.
+pub(crate) fn quarantine(xulstore_dir: &Path) -> XULStoreResult<PathBuf> {
+    RECOVERY_COUNT.fetch_add(1, Ordering::Relaxed);
+
+    let corrupt_dir = xulstore_dir.with_file_name("xulstore.corrupt");
+    if corrupt_dir.exists() {
+        std::fs::remove_dir_all(&corrupt_dir)?;
+    }
+
+    error!(
+        "xulstore environment at {:?} is corrupt; moving it to {:?} and starting fresh",
+        xulstore_dir, corrupt_dir
+    );
+    rename(xulstore_dir, &corrupt_dir)?;
+
+    Ok(corrupt_dir)
+}
+
+/// Returns true if `err`, encountered while opening or iterating an
+/// environment, looks like on-disk corruption rather than a transient or
+/// permission failure -- i.e. something quarantining and recreating the
+/// environment might actually fix.
+pub(crate) fn looks_like_corruption(err: &crate::error::XULStoreError) -> bool {
+    use crate::error::XULStoreError::*;
+    match err {
+        RkvStoreError(_) | UnexpectedValue | ConvertBytes(_) | ConvertString(_) => true,
+        _ => false,
+    }
+}
@@ -2,13 +2,22 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
-use crate::{error::XULStoreError, error::XULStoreResult, ffi::ProfileChangeObserver, make_key, SEPARATOR};
+use crate::{
+    error::XULStoreError, error::XULStoreResult, ffi::ProfileChangeObserver, make_key, recovery,
+    value::XULStoreValue, SEPARATOR,
+};
 use nsstring::nsString;
-use rkv::{Manager, Rkv, SingleStore, StoreOptions, Value};
+#[cfg(feature = "safemode")]
+use rkv::backend::{SafeMode, SafeModeDatabase, SafeModeEnvironment};
+use rkv::{
+    backend::{Lmdb, LmdbDatabase, LmdbEnvironment},
+    Manager, Rkv, SingleStore, StoreOptions, Value,
+};
 use std::{
     collections::HashMap,
     ffi::CString,
     fs::{create_dir_all, remove_file, File},
+    convert::TryFrom,
     ops::DerefMut,
     path::PathBuf,
     str,
@@ -16,7 +25,35 @@ use std::{
 };
 use xpcom::{interfaces::nsIFile, XpCom};
 
-type XULStoreData = HashMap<String, HashMap<String, HashMap<String, String>>>;
+type XULStoreData = HashMap<String, HashMap<String, HashMap<String, XULStoreValue>>>;
+
+/// XULStore's storage environment.  Ordinarily this is the LMDB-backed
+/// environment, same as it's always been, but when the `safemode` feature
+/// is enabled and LMDB fails us (a corrupt `.mdb` file, a filesystem LMDB
+/// can't mmap on, ...) we fall back to rkv's pure-Rust SafeMode environment
+/// so the profile still gets a working store.
+pub(crate) enum RkvEnvironment {
+    Lmdb(Arc<RwLock<Rkv<LmdbEnvironment>>>),
+    #[cfg(feature = "safemode")]
+    Safe(Arc<RwLock<Rkv<SafeModeEnvironment>>>),
+}
+
+pub(crate) enum RkvStore {
+    Lmdb(SingleStore<LmdbDatabase>),
+    #[cfg(feature = "safemode")]
+    Safe(SingleStore<SafeModeDatabase>),
+}
+
+impl Clone for RkvStore {
+    fn clone(&self) -> RkvStore {
+        match self {
+            RkvStore::Lmdb(store) => RkvStore::Lmdb(*store),
+            #[cfg(feature = "safemode")]
+            RkvStore::Safe(store) => RkvStore::Safe(*store),
+        }
+    }
+}
+impl Copy for RkvStore {}
 
 lazy_static! {
     pub(crate) static ref PROFILE_DIR: RwLock<Option<PathBuf>> = {
@@ -24,13 +61,11 @@ lazy_static! {
         RwLock::new(get_profile_dir().ok())
     };
 
-    #[derive(Debug)]
-    pub(crate) static ref RKV: RwLock<Option<Arc<RwLock<Rkv>>>> = {
+    pub(crate) static ref RKV: RwLock<Option<Arc<RwLock<RkvEnvironment>>>> = {
         RwLock::new(get_rkv().ok())
     };
 
-    #[derive(Debug)]
-    pub(crate) static ref STORE: RwLock<Option<SingleStore>> = {
+    pub(crate) static ref STORE: RwLock<Option<RkvStore>> = {
         RwLock::new(match get_store() {
             Ok(store) => {
                 maybe_migrate_data(store);
@@ -85,25 +120,60 @@ fn get_xulstore_dir() -> XULStoreResult<PathBuf> {
     Ok(xulstore_dir)
 }
 
-pub(crate) fn get_rkv() -> XULStoreResult<Arc<RwLock<Rkv>>> {
-    let mut manager = Manager::singleton().write()?;
+pub(crate) fn get_rkv() -> XULStoreResult<Arc<RwLock<RkvEnvironment>>> {
     let xulstore_dir = get_xulstore_dir()?;
-    manager
-        .get_or_create(xulstore_dir.as_path(), Rkv::new)
-        .map_err(|err| err.into())
+
+    match get_rkv_lmdb(&xulstore_dir) {
+        Ok(rkv) => return Ok(rkv),
+        Err(err) if recovery::looks_like_corruption(&err) => {
+            recovery::quarantine(&xulstore_dir)?;
+            create_dir_all(&xulstore_dir)?;
+            if let Ok(rkv) = get_rkv_lmdb(&xulstore_dir) {
+                return Ok(rkv);
+            }
+        }
+        Err(_) => (),
+    }
+
+    #[cfg(feature = "safemode")]
+    {
+        warn!("error opening LMDB environment, falling back to SafeMode");
+        return get_rkv_safe(&xulstore_dir);
+    }
+
+    #[cfg(not(feature = "safemode"))]
+    Err(XULStoreError::Unavailable)
 }
 
-pub(crate) fn get_store() -> XULStoreResult<SingleStore> {
+fn get_rkv_lmdb(xulstore_dir: &PathBuf) -> XULStoreResult<Arc<RwLock<RkvEnvironment>>> {
+    let mut manager = Manager::<LmdbEnvironment>::singleton().write()?;
+    let rkv = manager.get_or_create(xulstore_dir.as_path(), Rkv::new)?;
+    Ok(Arc::new(RwLock::new(RkvEnvironment::Lmdb(rkv))))
+}
+
+#[cfg(feature = "safemode")]
+fn get_rkv_safe(xulstore_dir: &PathBuf) -> XULStoreResult<Arc<RwLock<RkvEnvironment>>> {
+    let mut manager = Manager::<SafeModeEnvironment>::singleton().write()?;
+    let rkv = manager.get_or_create(xulstore_dir.as_path(), Rkv::new)?;
+    Ok(Arc::new(RwLock::new(RkvEnvironment::Safe(rkv))))
+}
+
+pub(crate) fn get_store() -> XULStoreResult<RkvStore> {
     let rkv_guard = RKV.read()?;
-    let rkv = rkv_guard
-        .as_ref()
-        .ok_or(XULStoreError::Unavailable)?
-        .read()?;
-    rkv.open_single("db", StoreOptions::create())
-        .map_err(|err| err.into())
+    let env = rkv_guard.as_ref().ok_or(XULStoreError::Unavailable)?;
+
+    match &*env.read()? {
+        RkvEnvironment::Lmdb(rkv) => Ok(RkvStore::Lmdb(
+            rkv.read()?.open_single("db", StoreOptions::create())?,
+        )),
+        #[cfg(feature = "safemode")]
+        RkvEnvironment::Safe(rkv) => Ok(RkvStore::Safe(
+            rkv.read()?.open_single("db", StoreOptions::create())?,
+        )),
+    }
 }
 
-fn maybe_migrate_data(store: SingleStore) {
+fn maybe_migrate_data(store: RkvStore) {
     // Failure to migrate data isn't fatal, so we don't return a result.
     // But we use a closure returning a result to enable use of the ? operator.
     (|| -> XULStoreResult<()> {
@@ -123,23 +193,37 @@ fn maybe_migrate_data(store: SingleStore) {
             serde_json::from_reader(file)?;
 
         let rkv_guard = RKV.read()?;
-        let rkv = rkv_guard
-            .as_ref()
-            .ok_or(XULStoreError::Unavailable)?
-            .read()?;
-        let mut writer = rkv.write()?;
-
-        for (doc, ids) in json {
-            for (id, attrs) in ids {
-                for (attr, value) in attrs {
-                    let key = make_key(&doc, &id, &attr);
-                    store.put(&mut writer, &key, &Value::Str(&value))?;
+        let env = rkv_guard.as_ref().ok_or(XULStoreError::Unavailable)?;
+
+        match (&*env.read()?, store) {
+            (RkvEnvironment::Lmdb(rkv), RkvStore::Lmdb(store)) => {
+                let mut writer = rkv.write()?.write()?;
+                for (doc, ids) in json {
+                    for (id, attrs) in ids {
+                        for (attr, value) in attrs {
+                            let key = make_key(&doc, &id, &attr);
+                            store.put(&mut writer, &key, &Value::Str(&value))?;
+                        }
+                    }
                 }
+                writer.commit()?;
             }
+            #[cfg(feature = "safemode")]
+            (RkvEnvironment::Safe(rkv), RkvStore::Safe(store)) => {
+                let mut writer = rkv.write()?.write()?;
+                for (doc, ids) in json {
+                    for (id, attrs) in ids {
+                        for (attr, value) in attrs {
+                            let key = make_key(&doc, &id, &attr);
+                            store.put(&mut writer, &key, &Value::Str(&value))?;
+                        }
+                    }
+                }
+                writer.commit()?;
+            }
+            _ => return Err(XULStoreError::Unavailable),
         }
 
-        writer.commit()?;
-
         remove_file(old_datastore)?;
 
         Ok(())
@@ -199,44 +283,26 @@ pub(crate) fn update_profile_dir() {
         }
 
         let mut data_guard = DATA.write()?;
-        *data_guard = get_data().ok();
+        // Use the non-recovering variant: get_data() calls update_profile_dir()
+        // on its own corruption retry, and calling back into get_data() here
+        // would turn that single retry into unbounded mutual recursion on
+        // persistent corruption.
+        *data_guard = get_data_no_recovery().ok();
 
         Ok(())
     })()
     .unwrap_or_else(|err| error!("error updating profile dir: {}", err));
 }
 
-fn unwrap_value(value: &Option<Value>) -> XULStoreResult<String> {
-    match value {
-        Some(Value::Str(val)) => Ok(val.to_string()),
-
-        // Per the XULStore API, return an empty string if the value
-        // isn't found.
-        None => Ok("".to_owned()),
-
-        // This should never happen, but it could happen in theory
-        // if someone writes a different kind of value into the store
-        // using a more general API (kvstore, rkv, LMDB).
-        Some(_) => Err(XULStoreError::UnexpectedValue),
-    }
-}
-
-fn get_data() -> XULStoreResult<XULStoreData> {
-    let store = *STORE.read()?.as_ref().ok_or(XULStoreError::Unavailable)?;
-    let rkv_guard = RKV.read()?;
-    let rkv = rkv_guard
-        .as_ref()
-        .ok_or(XULStoreError::Unavailable)?
-        .read()?;
-    let reader = rkv.read()?;
-    let mut all = HashMap::new();
-    let iterator = store.iter_start(&reader)?;
-
+fn collect_data<'r, I>(iterator: I, all: &mut XULStoreData) -> XULStoreResult<()>
+where
+    I: Iterator<Item = Result<(&'r [u8], Option<Value<'r>>), rkv::StoreError>>,
+{
     for result in iterator {
-        let (key, value): (&str, String) = match result {
+        let (key, value): (&str, XULStoreValue) = match result {
             Ok((key, value)) => {
                 assert!(value.is_some(), "iterated key has value");
-                match (str::from_utf8(&key), unwrap_value(&value)) {
+                match (str::from_utf8(&key), XULStoreValue::try_from(value)) {
                     (Ok(key), Ok(value)) => (key, value),
                     (Err(err), _) => return Err(err.into()),
                     (_, Err(err)) => return Err(err.into()),
@@ -257,5 +323,155 @@ fn get_data() -> XULStoreResult<XULStoreData> {
         id.entry(attr_name).or_insert(value);
     }
 
+    Ok(())
+}
+
+/// Applies a whole batch of pending `(key -> Some(value) | None)` mutations
+/// in a single writer/commit, across whichever backend is active.  `None`
+/// means the key was deleted.  This is the single-transaction primitive the
+/// write-coalescing layer flushes pending writes through.
+pub(crate) fn apply_batch(batch: &HashMap<String, Option<XULStoreValue>>) -> XULStoreResult<()> {
+    let store = STORE.read()?.as_ref().ok_or(XULStoreError::Unavailable)?.clone();
+    let rkv_guard = RKV.read()?;
+    let env = rkv_guard.as_ref().ok_or(XULStoreError::Unavailable)?;
+
+    match (&*env.read()?, store) {
+        (RkvEnvironment::Lmdb(rkv), RkvStore::Lmdb(store)) => {
+            let mut writer = rkv.write()?.write()?;
+            for (key, value) in batch {
+                match value {
+                    Some(value) => store.put(&mut writer, key, &value.as_value())?,
+                    None => match store.delete(&mut writer, key) {
+                        Ok(()) | Err(rkv::StoreError::LmdbError(lmdb::Error::NotFound)) => (),
+                        Err(err) => return Err(err.into()),
+                    },
+                }
+            }
+            writer.commit()?;
+        }
+        #[cfg(feature = "safemode")]
+        (RkvEnvironment::Safe(rkv), RkvStore::Safe(store)) => {
+            let mut writer = rkv.write()?.write()?;
+            for (key, value) in batch {
+                match value {
+                    Some(value) => store.put(&mut writer, key, &value.as_value())?,
+                    None => store.delete(&mut writer, key)?,
+                }
+            }
+            writer.commit()?;
+        }
+        _ => return Err(XULStoreError::Unavailable),
+    }
+
+    Ok(())
+}
+
+pub(crate) fn get_data() -> XULStoreResult<XULStoreData> {
+    match get_data_no_recovery() {
+        Ok(all) => Ok(all),
+        Err(err) if recovery::looks_like_corruption(&err) => {
+            // A bad key or value turned up mid-iteration: treat the whole
+            // environment as suspect, quarantine it, and start over with a
+            // fresh (and therefore empty, pending re-migration) store. This
+            // retry happens at most once: update_profile_dir() repopulates
+            // DATA through get_data_no_recovery(), not get_data(), so a
+            // second round of corruption here returns an error instead of
+            // recursing back into this recovery path.
+            let xulstore_dir = get_xulstore_dir()?;
+            recovery::quarantine(&xulstore_dir)?;
+            warn!(
+                "xulstore recovered from corruption ({} times this session)",
+                recovery::recovery_count()
+            );
+            update_profile_dir();
+            get_data_no_recovery()
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// `get_data()` without its one-shot corruption recovery, for callers (like
+/// `update_profile_dir()`, which recovery itself calls) that would otherwise
+/// recurse back into that recovery path.
+fn get_data_no_recovery() -> XULStoreResult<XULStoreData> {
+    let mut all = get_data_inner()?;
+
+    // Writes still sitting in the write-coalescing batch haven't hit the
+    // store yet; overlay them so enumeration doesn't miss the caller's own
+    // unflushed writes.
+    apply_pending(&mut all);
+
+    Ok(all)
+}
+
+fn apply_pending(all: &mut XULStoreData) {
+    for (key, value) in crate::pending::snapshot() {
+        let parts = key.split(SEPARATOR).collect::<Vec<&str>>();
+        if parts.len() != 3 {
+            continue;
+        }
+        let (doc_url, element_id, attr_name) = (parts[0].to_owned(), parts[1].to_owned(), parts[2].to_owned());
+
+        match value {
+            Some(value) => {
+                let doc = all.entry(doc_url).or_insert_with(HashMap::new);
+                let id = doc.entry(element_id).or_insert_with(HashMap::new);
+                id.insert(attr_name, value);
+            }
+            None => {
+                if let Some(doc) = all.get_mut(&doc_url) {
+                    if let Some(id) = doc.get_mut(&element_id) {
+                        id.remove(&attr_name);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Looks up `key` in the store and hands the raw `rkv::Value` to `read`,
+/// for callers (like the async task module) that want to reuse the
+/// backend-dispatch boilerplate without going through the cached `DATA`
+/// snapshot.
+pub(crate) fn with_reader<R>(
+    env: &Arc<RwLock<RkvEnvironment>>,
+    store: RkvStore,
+    read: impl FnOnce(Option<Value>) -> XULStoreResult<R>,
+    key: &str,
+) -> XULStoreResult<R> {
+    match (&*env.read()?, store) {
+        (RkvEnvironment::Lmdb(rkv), RkvStore::Lmdb(store)) => {
+            let reader = rkv.read()?.read()?;
+            read(store.get(&reader, key)?)
+        }
+        #[cfg(feature = "safemode")]
+        (RkvEnvironment::Safe(rkv), RkvStore::Safe(store)) => {
+            let reader = rkv.read()?.read()?;
+            read(store.get(&reader, key)?)
+        }
+        _ => Err(XULStoreError::Unavailable),
+    }
+}
+
+pub(crate) fn get_data_inner() -> XULStoreResult<XULStoreData> {
+    let store = STORE.read()?.as_ref().ok_or(XULStoreError::Unavailable)?.clone();
+    let rkv_guard = RKV.read()?;
+    let env = rkv_guard.as_ref().ok_or(XULStoreError::Unavailable)?;
+
+    let mut all = HashMap::new();
+
+    match (&*env.read()?, store) {
+        (RkvEnvironment::Lmdb(rkv), RkvStore::Lmdb(store)) => {
+            let reader = rkv.read()?.read()?;
+            collect_data(store.iter_start(&reader)?, &mut all)?;
+        }
+        #[cfg(feature = "safemode")]
+        (RkvEnvironment::Safe(rkv), RkvStore::Safe(store)) => {
+            let reader = rkv.read()?.read()?;
+            collect_data(store.iter_start(&reader)?, &mut all)?;
+        }
+        _ => return Err(XULStoreError::Unavailable),
+    }
+
     Ok(all)
 }
@@ -0,0 +1,305 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! An async, callback-based front door onto XULStore, for callers that
+//! don't want to block the calling (usually main) thread on I/O the way
+//! `get_data`/`DATA` do.  Each operation is a `moz_task::Task` dispatched to
+//! a dedicated storage thread; it does its work against the same `STORE`
+//! the synchronous API uses, then redispatches back to the caller's thread
+//! to invoke an XPCOM callback.  This mirrors the kvstore bridge's
+//! `nsIKeyValue*Callback` pattern.
+
+use crate::{
+    error::XULStoreError, make_key, statics::RKV, statics::STORE, value::XULStoreValue,
+};
+use moz_task::{create_thread, Task, ThreadBoundRefPtr};
+use nsstring::{nsCString, nsString};
+use std::{
+    cell::{Cell, RefCell},
+    convert::TryFrom,
+};
+use xpcom::{
+    interfaces::{
+        nsIThread, nsIXULStoreEnumeratorCallback, nsIXULStoreStringCallback,
+        nsIXULStoreVoidCallback,
+    },
+    RefPtr,
+};
+
+lazy_static! {
+    /// The thread async XULStore operations are dispatched to.  A single
+    /// dedicated thread (rather than the shared background task queue) is
+    /// enough, since XULStore writes are small and infrequent.
+    static ref STORAGE_THREAD: moz_task::Result<RefPtr<nsIThread>> = create_thread("XULStore");
+}
+
+fn storage_thread() -> Result<RefPtr<nsIThread>, XULStoreError> {
+    match &*STORAGE_THREAD {
+        Ok(thread) => Ok(thread.clone()),
+        Err(result) => Err(XULStoreError::from(*result)),
+    }
+}
+
+pub(crate) struct GetTask {
+    doc: String,
+    id: String,
+    attr: String,
+    callback: ThreadBoundRefPtr<nsIXULStoreStringCallback>,
+    result: Cell<Option<Result<String, XULStoreError>>>,
+}
+
+impl GetTask {
+    pub(crate) fn new(
+        doc: String,
+        id: String,
+        attr: String,
+        callback: RefPtr<nsIXULStoreStringCallback>,
+    ) -> GetTask {
+        GetTask {
+            doc,
+            id,
+            attr,
+            callback: ThreadBoundRefPtr::new(callback),
+            result: Cell::new(None),
+        }
+    }
+
+    pub(crate) fn dispatch(self) -> Result<(), XULStoreError> {
+        let thread = storage_thread()?;
+        let runnable = moz_task::TaskRunnable::new(Box::new(self))?;
+        runnable.dispatch(&thread)?;
+        Ok(())
+    }
+}
+
+impl Task for GetTask {
+    fn run(&self) {
+        let key = make_key(&self.doc, &self.id, &self.attr);
+        let result = (|| -> Result<XULStoreValue, XULStoreError> {
+            // A write for this key may still be sitting in the
+            // write-coalescing batch rather than committed to the store;
+            // check that first so this doesn't read stale data out from
+            // under a write it raced with.
+            if let Some(pending) = crate::pending::peek(&key) {
+                return Ok(pending.unwrap_or_else(|| XULStoreValue::Str(String::new())));
+            }
+
+            let store = STORE.read()?.as_ref().ok_or(XULStoreError::Unavailable)?.clone();
+            let rkv_guard = RKV.read()?;
+            let env = rkv_guard.as_ref().ok_or(XULStoreError::Unavailable)?;
+            crate::statics::with_reader(env, store, |value| XULStoreValue::try_from(value), &key)
+        })();
+        self.result.set(Some(result));
+    }
+
+    fn done(&self) -> moz_task::Result<()> {
+        let callback = match self.callback.get_ref() {
+            Some(callback) => callback,
+            None => return Ok(()),
+        };
+        match self.result.take().expect("GetTask ran before done() was called") {
+            // The async API is still string-only for now; typed values are
+            // rendered the same way the legacy synchronous API does.
+            Ok(value) => unsafe { callback.Resolve(&*nsString::from(value.to_legacy_string())) },
+            Err(err) => unsafe { callback.Reject(err.into()) },
+        };
+        Ok(())
+    }
+
+    fn name(&self) -> &str {
+        "XULStore::GetTask"
+    }
+}
+
+pub(crate) struct SetTask {
+    doc: String,
+    id: String,
+    attr: String,
+    value: XULStoreValue,
+    callback: ThreadBoundRefPtr<nsIXULStoreVoidCallback>,
+    result: Cell<Option<Result<(), XULStoreError>>>,
+}
+
+impl SetTask {
+    pub(crate) fn new(
+        doc: String,
+        id: String,
+        attr: String,
+        value: XULStoreValue,
+        callback: RefPtr<nsIXULStoreVoidCallback>,
+    ) -> SetTask {
+        SetTask {
+            doc,
+            id,
+            attr,
+            value,
+            callback: ThreadBoundRefPtr::new(callback),
+            result: Cell::new(None),
+        }
+    }
+
+    pub(crate) fn dispatch(self) -> Result<(), XULStoreError> {
+        let thread = storage_thread()?;
+        let runnable = moz_task::TaskRunnable::new(Box::new(self))?;
+        runnable.dispatch(&thread)?;
+        Ok(())
+    }
+}
+
+impl Task for SetTask {
+    // Queuing (rather than committing here) means this always resolves
+    // Ok: the write-coalescing layer batches this with any other pending
+    // mutations and commits them together later, on its own queue. If
+    // that later commit fails, there's no way to report it back through
+    // this callback -- it's already resolved by the time the failure is
+    // known -- so it's only logged and counted via
+    // pending::flush_failure_count(), same as any other queued write.
+    fn run(&self) {
+        crate::pending::queue_write(&self.doc, &self.id, &self.attr, self.value.clone());
+        self.result.set(Some(Ok(())));
+    }
+
+    fn done(&self) -> moz_task::Result<()> {
+        let callback = match self.callback.get_ref() {
+            Some(callback) => callback,
+            None => return Ok(()),
+        };
+        match self.result.take().expect("SetTask ran before done() was called") {
+            Ok(()) => unsafe { callback.Resolve() },
+            Err(err) => unsafe { callback.Reject(err.into()) },
+        };
+        Ok(())
+    }
+
+    fn name(&self) -> &str {
+        "XULStore::SetTask"
+    }
+}
+
+pub(crate) struct RemoveTask {
+    doc: String,
+    id: String,
+    attr: String,
+    callback: ThreadBoundRefPtr<nsIXULStoreVoidCallback>,
+    result: Cell<Option<Result<(), XULStoreError>>>,
+}
+
+impl RemoveTask {
+    pub(crate) fn new(
+        doc: String,
+        id: String,
+        attr: String,
+        callback: RefPtr<nsIXULStoreVoidCallback>,
+    ) -> RemoveTask {
+        RemoveTask {
+            doc,
+            id,
+            attr,
+            callback: ThreadBoundRefPtr::new(callback),
+            result: Cell::new(None),
+        }
+    }
+
+    pub(crate) fn dispatch(self) -> Result<(), XULStoreError> {
+        let thread = storage_thread()?;
+        let runnable = moz_task::TaskRunnable::new(Box::new(self))?;
+        runnable.dispatch(&thread)?;
+        Ok(())
+    }
+}
+
+impl Task for RemoveTask {
+    // See SetTask::run: queuing the delete means this always resolves Ok,
+    // and a later commit failure can only be observed via
+    // pending::flush_failure_count(), not through this callback.
+    fn run(&self) {
+        crate::pending::queue_delete(&self.doc, &self.id, &self.attr);
+        self.result.set(Some(Ok(())));
+    }
+
+    fn done(&self) -> moz_task::Result<()> {
+        let callback = match self.callback.get_ref() {
+            Some(callback) => callback,
+            None => return Ok(()),
+        };
+        match self.result.take().expect("RemoveTask ran before done() was called") {
+            Ok(()) => unsafe { callback.Resolve() },
+            Err(err) => unsafe { callback.Reject(err.into()) },
+        };
+        Ok(())
+    }
+
+    fn name(&self) -> &str {
+        "XULStore::RemoveTask"
+    }
+}
+
+pub(crate) struct EnumerateTask {
+    doc: String,
+    callback: ThreadBoundRefPtr<nsIXULStoreEnumeratorCallback>,
+    result: RefCell<Option<Result<Vec<(String, String)>, XULStoreError>>>,
+}
+
+impl EnumerateTask {
+    pub(crate) fn new(doc: String, callback: RefPtr<nsIXULStoreEnumeratorCallback>) -> EnumerateTask {
+        EnumerateTask {
+            doc,
+            callback: ThreadBoundRefPtr::new(callback),
+            result: RefCell::new(None),
+        }
+    }
+
+    pub(crate) fn dispatch(self) -> Result<(), XULStoreError> {
+        let thread = storage_thread()?;
+        let runnable = moz_task::TaskRunnable::new(Box::new(self))?;
+        runnable.dispatch(&thread)?;
+        Ok(())
+    }
+}
+
+impl Task for EnumerateTask {
+    fn run(&self) {
+        let result = crate::statics::get_data()
+            .map_err(XULStoreError::from)
+            .map(|data| {
+                data.get(&self.doc)
+                    .cloned()
+                    .unwrap_or_default()
+                    .into_iter()
+                    .flat_map(|(id, attrs)| {
+                        attrs.into_iter().map(move |(attr, value)| {
+                            (format!("{}\u{1e}{}", id, attr), value.to_legacy_string())
+                        })
+                    })
+                    .collect()
+            });
+        *self.result.borrow_mut() = Some(result);
+    }
+
+    fn done(&self) -> moz_task::Result<()> {
+        let callback = match self.callback.get_ref() {
+            Some(callback) => callback,
+            None => return Ok(()),
+        };
+        match self
+            .result
+            .borrow_mut()
+            .take()
+            .expect("EnumerateTask ran before done() was called")
+        {
+            Ok(pairs) => {
+                for (key, value) in pairs {
+                    unsafe { callback.OnEntry(&*nsCString::from(key), &*nsString::from(value)) };
+                }
+                unsafe { callback.Resolve() };
+            }
+            Err(err) => unsafe { callback.Reject(err.into()) },
+        };
+        Ok(())
+    }
+
+    fn name(&self) -> &str {
+        "XULStore::EnumerateTask"
+    }
+}
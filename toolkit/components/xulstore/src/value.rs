@@ -0,0 +1,72 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! XULStore used to only ever persist strings; `rkv`/LMDB can do better.
+//! `XULStoreValue` is an owned, `'static` counterpart to `rkv::Value` (the
+//! same relationship `OwnedValue` has to `Value` in the kvstore bridge) so
+//! the store can hold ints, floats, and bools without every consumer
+//! re-serializing them to strings, while still reading back values written
+//! by older XULStore versions -- which are always strings -- transparently.
+
+use crate::error::{XULStoreError, XULStoreResult};
+use rkv::Value;
+use std::convert::TryFrom;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum XULStoreValue {
+    Str(String),
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+}
+
+impl XULStoreValue {
+    pub(crate) fn as_value(&self) -> Value {
+        match self {
+            XULStoreValue::Str(value) => Value::Str(value),
+            XULStoreValue::Int(value) => Value::I64(*value),
+            XULStoreValue::Float(value) => Value::F64((*value).into()),
+            XULStoreValue::Bool(value) => Value::Bool(*value),
+        }
+    }
+
+    /// Renders the value the way the (string-only) legacy XULStore API
+    /// expects, for callers that haven't been updated to the typed API yet.
+    pub fn to_legacy_string(&self) -> String {
+        match self {
+            XULStoreValue::Str(value) => value.clone(),
+            XULStoreValue::Int(value) => value.to_string(),
+            XULStoreValue::Float(value) => value.to_string(),
+            XULStoreValue::Bool(value) => value.to_string(),
+        }
+    }
+}
+
+impl<'a> TryFrom<Option<Value<'a>>> for XULStoreValue {
+    type Error = XULStoreError;
+
+    fn try_from(value: Option<Value<'a>>) -> XULStoreResult<XULStoreValue> {
+        match value {
+            Some(Value::Str(value)) => Ok(XULStoreValue::Str(value.to_owned())),
+            Some(Value::I64(value)) => Ok(XULStoreValue::Int(value)),
+            Some(Value::F64(value)) => Ok(XULStoreValue::Float(value.into_inner())),
+            Some(Value::Bool(value)) => Ok(XULStoreValue::Bool(value)),
+
+            // Per the XULStore API, return an empty string if the value
+            // isn't found.
+            None => Ok(XULStoreValue::Str(String::new())),
+
+            // This should never happen, but it could happen in theory if
+            // someone writes a different kind of value into the store
+            // using a more general API (kvstore, rkv, LMDB).
+            Some(_) => Err(XULStoreError::UnexpectedValue),
+        }
+    }
+}
+
+impl From<String> for XULStoreValue {
+    fn from(value: String) -> XULStoreValue {
+        XULStoreValue::Str(value)
+    }
+}
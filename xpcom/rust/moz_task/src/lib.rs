@@ -0,0 +1,173 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Helpers for dispatching work to a background thread and getting the
+//! result back on the thread that requested it.
+//!
+//! A consumer implements `Task` for whatever it needs to do in the
+//! background (look up a database record, open a file, ...), wraps it in a
+//! `TaskRunnable`, and dispatches that runnable to a target thread.  The
+//! runnable runs the task once on the target thread, then redispatches
+//! itself back to the thread it was created on so the task can hand its
+//! result back to its caller (typically by invoking an XPCOM callback).
+//!
+//! `Task` itself doesn't know or care what kind of result a given task
+//! produces -- a task that returns nothing, an `nsIVariant`, an enumerator,
+//! or a database all implement the same trait, because the result lives in
+//! the concrete `Task` implementation (usually behind a `Cell`/`RefCell`)
+//! rather than in `TaskRunnable`.  That keeps `TaskRunnable` itself a plain,
+//! non-generic `#[derive(xpcom)]` type, which Rust doesn't allow to be
+//! generic.
+//!
+//! When a task's result (or the callback it reports through) is itself an
+//! XPCOM pointer, wrap it in [`ThreadBoundRefPtr`] before stashing it: an
+//! XPCOM `RefPtr` isn't safe to addref/release off the thread that created
+//! it, and `ThreadBoundRefPtr` enforces that.
+
+#![allow(non_snake_case)]
+
+extern crate libc;
+#[macro_use]
+extern crate log;
+#[macro_use]
+extern crate xpcom;
+
+mod threadboundrefptr;
+
+pub use threadboundrefptr::ThreadBoundRefPtr;
+
+use nserror::{nsresult, NsresultExt, NS_ERROR_FAILURE, NS_OK};
+use nsstring::{nsACString, nsCString};
+use std::{cell::Cell, fmt::Write, ptr, result};
+use xpcom::{
+    getter_addrefs,
+    interfaces::{nsIEventTarget, nsIRunnable, nsIThread},
+    RefPtr,
+};
+
+pub type Result<T> = result::Result<T, nsresult>;
+
+extern "C" {
+    fn NS_GetCurrentThreadEventTarget(result: *mut *const nsIThread) -> nsresult;
+    fn NS_NewNamedThreadWithDefaultStackSize(
+        name: *const nsACString,
+        result: *mut *const nsIThread,
+        event: *const nsIRunnable,
+    ) -> nsresult;
+    fn NS_CreateBackgroundTaskQueue(
+        name: *const nsACString,
+        result: *mut *const nsIEventTarget,
+    ) -> nsresult;
+}
+
+/// Returns a handle to the current thread.
+pub fn get_current_thread() -> Result<RefPtr<nsIThread>> {
+    getter_addrefs(|p| unsafe { NS_GetCurrentThreadEventTarget(p) })
+}
+
+/// Creates a new named thread with the platform's default stack size.
+pub fn create_thread(name: &str) -> Result<RefPtr<nsIThread>> {
+    let name: nsCString = name.into();
+    getter_addrefs(|p| unsafe { NS_NewNamedThreadWithDefaultStackSize(&*name, p, ptr::null()) })
+}
+
+/// Creates a serial event target backed by the shared background thread
+/// pool, suitable for dispatching a consumer's tasks without the overhead
+/// of a dedicated thread.
+pub fn create_background_task_queue(name: &str) -> Result<RefPtr<nsIEventTarget>> {
+    let name: nsCString = name.into();
+    getter_addrefs(|p| unsafe { NS_CreateBackgroundTaskQueue(&*name, p) })
+}
+
+/// A task that runs once on a target thread and then reports back on the
+/// thread that created it.
+///
+/// Implementations are responsible for stashing whatever result `run`
+/// produces (a value, an error, nothing at all) somewhere `done` can pick it
+/// up -- typically in a `Cell<Option<Result<Output>>>` field of the
+/// implementing type.  This is what lets the same `TaskRunnable` dispatch
+/// machinery serve tasks with completely different result types: a task
+/// returning a database handle, one returning an enumerator, and one
+/// returning nothing all implement `Task` the same way.
+pub trait Task {
+    /// Runs on the target thread.
+    fn run(&self);
+
+    /// Runs on the thread that dispatched this task, once `run` has
+    /// returned, so the task can hand its result back to its caller (e.g.
+    /// by invoking an XPCOM callback).  The `nsresult` it returns becomes
+    /// the result of the runnable's `Run` method.
+    fn done(&self) -> Result<()>;
+
+    /// A short name used for profiler markers and `nsINamed`.
+    fn name(&self) -> &str {
+        "TaskRunnable"
+    }
+}
+
+#[derive(xpcom)]
+#[xpimplements(nsIRunnable, nsINamed)]
+#[refcnt = "atomic"]
+pub struct InitTaskRunnable {
+    source: RefPtr<nsIThread>,
+    task: Box<dyn Task + Send>,
+
+    /// Tracks which half of the runnable (the target-thread `run` or the
+    /// source-thread `done`) should execute the next time `Run` is called.
+    has_run: Cell<bool>,
+}
+
+// SAFETY: a `TaskRunnable` is dispatched to the target thread, runs `task`
+// there, then redispatches itself back to the source thread to run `task`
+// again -- each dispatch is a synchronization point, so `task` is only ever
+// touched by one thread at a time despite most `Task` implementations
+// stashing their result in a `Cell`/`RefCell`, which is `!Sync`. That makes
+// `TaskRunnable` itself safe to share across threads even though `task`
+// isn't `Sync`.
+unsafe impl Sync for TaskRunnable {}
+
+impl TaskRunnable {
+    pub fn new(task: Box<dyn Task + Send>) -> Result<RefPtr<TaskRunnable>> {
+        let source = get_current_thread()?;
+        Ok(TaskRunnable::allocate(InitTaskRunnable {
+            source,
+            task,
+            has_run: Cell::new(false),
+        }))
+    }
+
+    /// Dispatches this runnable to `target`, which should run `task.run()`
+    /// and then redispatch back to the thread that called `new` to invoke
+    /// `task.done()`.
+    pub fn dispatch(self: RefPtr<Self>, target: &nsIEventTarget) -> Result<()> {
+        unsafe { target.DispatchFromScript(self.coerce(), 0) }.to_result()
+    }
+
+    unsafe fn Run(&self) -> nsresult {
+        if !self.has_run.replace(true) {
+            // We're on the target thread: do the work, then redispatch
+            // ourselves back to the thread that dispatched us so `done` can
+            // run there.
+            self.task.run();
+            let target = match getter_addrefs(|p| self.source.GetEventTarget(p)) {
+                Ok(target) => target,
+                Err(result) => return result,
+            };
+            target.DispatchFromScript(self.coerce(), 0)
+        } else {
+            // We're back on the source thread: hand the result to the task.
+            match self.task.done() {
+                Ok(()) => NS_OK,
+                Err(result) => result,
+            }
+        }
+    }
+
+    unsafe fn GetName(&self, name: *mut nsACString) -> nsresult {
+        match write!(*name, "{}", self.task.name()) {
+            Ok(()) => NS_OK,
+            Err(_) => NS_ERROR_FAILURE,
+        }
+    }
+}
@@ -0,0 +1,69 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! `RefPtr<T>` to an XPCOM object isn't safe to addref/release off the
+//! thread that owns it, so it can't simply be stuffed into a `Task` that
+//! runs on a background thread and hands its result back on another.
+//! `ThreadBoundRefPtr` pins the pointer to its owning thread and only lets
+//! callers get at it there, so a `Task` can carry an XPCOM pointer (a
+//! callback, an `nsIVariant` result, ...) across the dispatch without
+//! risking an off-thread addref/release.
+
+use std::thread::{self, ThreadId};
+use xpcom::RefPtr;
+
+pub struct ThreadBoundRefPtr<T> {
+    owning_thread: ThreadId,
+    ptr: Option<RefPtr<T>>,
+}
+
+impl<T> ThreadBoundRefPtr<T> {
+    /// Wraps `ptr`, pinning it to the thread this is called on.
+    pub fn new(ptr: RefPtr<T>) -> ThreadBoundRefPtr<T> {
+        ThreadBoundRefPtr {
+            owning_thread: thread::current().id(),
+            ptr: Some(ptr),
+        }
+    }
+
+    /// Returns the wrapped pointer, or `None` if called from a thread other
+    /// than the one that created this `ThreadBoundRefPtr`.
+    pub fn get_ref(&self) -> Option<&RefPtr<T>> {
+        if thread::current().id() == self.owning_thread {
+            self.ptr.as_ref()
+        } else {
+            None
+        }
+    }
+
+    /// Drops the wrapped pointer now, on the current thread, instead of
+    /// waiting for `Drop`.  Useful when a `ThreadBoundRefPtr` outlives the
+    /// work it was created for and would otherwise be dropped from the
+    /// wrong thread.
+    pub fn clear(&mut self) {
+        if thread::current().id() == self.owning_thread {
+            self.ptr.take();
+        } else {
+            error!("ThreadBoundRefPtr cleared from a thread other than its owning thread");
+        }
+    }
+}
+
+impl<T> Drop for ThreadBoundRefPtr<T> {
+    fn drop(&mut self) {
+        if self.ptr.is_some() && thread::current().id() != self.owning_thread {
+            // Releasing an XPCOM pointer from the wrong thread isn't
+            // memory-unsafe in the same way a use would be, but it's still
+            // a bug: leak rather than risk an off-thread release racing the
+            // owning thread.
+            error!("leaking a ThreadBoundRefPtr dropped from a thread other than its owning thread");
+            std::mem::forget(self.ptr.take());
+        }
+    }
+}
+
+// Safe because `get_ref` only ever hands back a reference on the owning
+// thread, and `Drop` refuses to release off-thread.
+unsafe impl<T> Send for ThreadBoundRefPtr<T> {}
+unsafe impl<T> Sync for ThreadBoundRefPtr<T> {}